@@ -1,9 +1,18 @@
 use anyhow::{Context, Result, bail};
 use clap::{Parser, Subcommand};
 use colored::*;
+use serde::{Deserialize, Serialize};
 use std::env;
+use std::fs;
+use std::io::{self, IsTerminal, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Child, Command, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum IssueBackend {
@@ -40,14 +49,69 @@ enum Commands {
         #[arg(short, long)]
         model: Option<String>,
 
-        /// AI agent to use: "claude" or "gemini"
+        /// AI agent to use: "claude" or "gemini". Pass it several times to race the agents on the issue
         #[arg(short, long, default_value = "claude")]
-        agent: String,
+        agent: Vec<String>,
+
+        /// Stack the issue on another issue: branch from its branch and open the PR against it
+        #[arg(long, value_name = "ISSUE_ID")]
+        on: Option<String>,
 
         /// Arguments to create a new issue if no ID is provided
         #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         create_args: Vec<String>,
     },
+    /// Re-run the agent on an issue's PR with its unresolved review comments and failing checks
+    Revise {
+        /// The issue ID
+        issue_id: String,
+
+        /// The model to use with the AI agent
+        #[arg(short, long)]
+        model: Option<String>,
+
+        /// AI agent to use: "claude" or "gemini"
+        #[arg(short, long, default_value = "claude")]
+        agent: String,
+    },
+    /// Run the agent on an issue's PR with the logs of its failing CI jobs
+    FixCi {
+        /// The issue ID
+        issue_id: String,
+
+        /// The model to use with the AI agent
+        #[arg(short, long)]
+        model: Option<String>,
+
+        /// AI agent to use: "claude" or "gemini"
+        #[arg(short, long, default_value = "claude")]
+        agent: String,
+
+        /// After each session, wait for the checks to finish and try again while they fail
+        #[arg(short, long)]
+        watch: bool,
+
+        /// Maximum number of agent sessions with --watch
+        #[arg(long, default_value_t = 3)]
+        max_attempts: u32,
+    },
+    /// Compare the agents racing on an issue: session state, diffstat, tests and CI
+    Compare {
+        /// The issue ID
+        issue_id: String,
+
+        /// Command to run in each candidate's worktree, e.g. "cargo test"
+        #[arg(short, long)]
+        test: Option<String>,
+    },
+    /// Keep one agent's result of a race and discard the others
+    Pick {
+        /// The issue ID
+        issue_id: String,
+
+        /// Agent whose branch becomes the issue branch
+        agent: String,
+    },
     /// Stop working on an issue (removes worktree and branch)
     Unstart {
         /// The issue ID
@@ -57,10 +121,161 @@ enum Commands {
     Merge {
         /// The issue ID
         issue_id: String,
+
+        /// Don't start the queued issues this merge unblocks
+        #[arg(long)]
+        no_start: bool,
     },
     /// Signal that work is done (closes the Gemini session)
     Done,
-    
+    /// Check that the tools fuzemill depends on are installed and configured
+    Doctor {
+        /// AI agent to check: "claude" or "gemini"
+        #[arg(short, long, default_value = "claude")]
+        agent: String,
+    },
+    /// Show the recorded agent session transcripts for an issue
+    Logs {
+        /// The issue ID
+        issue_id: String,
+
+        /// List the recorded runs instead of showing a transcript
+        #[arg(short, long)]
+        list: bool,
+
+        /// Run to show (as printed by --list). Defaults to the latest run
+        #[arg(short, long)]
+        run: Option<String>,
+
+        /// Print the transcript as recorded, including terminal escape sequences
+        #[arg(long)]
+        raw: bool,
+
+        /// Post a condensed transcript as a comment: "issue" or "pr"
+        #[arg(long, value_name = "TARGET")]
+        comment: Option<String>,
+    },
+    /// Send a follow-up instruction to a running agent session
+    Say {
+        /// The issue ID
+        issue_id: String,
+
+        /// The message to send
+        #[arg(required_unless_present = "file", conflicts_with = "file")]
+        message: Option<String>,
+
+        /// Read the message from a file ("-" for stdin)
+        #[arg(short, long)]
+        file: Option<PathBuf>,
+    },
+    /// Watch running sessions, notify when they end or stall and when PRs are opened or CI finishes, and enforce session limits
+    Watch {
+        /// Seconds between polls
+        #[arg(short, long, default_value_t = 30)]
+        interval: u64,
+    },
+    /// Start the next issue that is ready for an agent: open, unassigned, labeled for the queue, and not blocked
+    Next {
+        /// Only list the ready issues, in the order they would be started
+        #[arg(short, long)]
+        list: bool,
+    },
+    /// Keep starting ready issues, highest priority first, while fewer agents than the limit are running
+    RunQueue {
+        /// Seconds between checks for free slots and newly ready issues
+        #[arg(short, long, default_value_t = 60)]
+        interval: u64,
+
+        /// Fill the free slots once and exit
+        #[arg(long)]
+        once: bool,
+
+        /// Maximum number of agents running at once (overrides queue.concurrency)
+        #[arg(short = 'j', long)]
+        concurrency: Option<usize>,
+    },
+    /// Report token usage, cost, duration and outcomes of agent runs
+    Report {
+        /// Group by "agent", "model", "label", "issue", "day" or "month"
+        #[arg(short, long, default_value = "agent")]
+        by: String,
+
+        /// Only include runs started on or after this date (YYYY-MM-DD)
+        #[arg(long)]
+        since: Option<String>,
+
+        /// Only include runs started on or before this date (YYYY-MM-DD)
+        #[arg(long)]
+        until: Option<String>,
+
+        /// Output format: "table", "csv" or "json"
+        #[arg(short, long, default_value = "table")]
+        format: String,
+    },
+    /// List the agent-authored commits in a range, from their commit trailers
+    Audit {
+        /// Commits to check, as for 'git log' (e.g. "v1.2..HEAD")
+        #[arg(default_value = "HEAD")]
+        range: String,
+
+        /// Also list commits without agent trailers
+        #[arg(short, long)]
+        all: bool,
+
+        /// Output format: "table" or "json"
+        #[arg(short, long, default_value = "table")]
+        format: String,
+    },
+    /// Clean up leftovers from crashed or interrupted runs (worktrees, branches, sessions, status labels)
+    Gc {
+        /// Clean up without asking for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+
+    /// Run the agent command and record its exit code and last lines of output (used inside tmux sessions)
+    #[command(hide = true)]
+    RunAgent {
+        /// Directory of the run to record results in
+        #[arg(long)]
+        run_dir: PathBuf,
+
+        /// Number of trailing output lines to keep in the log
+        #[arg(long, default_value_t = 200)]
+        tail_lines: usize,
+
+        /// Shell command that starts the agent
+        command: String,
+    },
+    /// Run the agent command inside the configured sandbox (used in place of the agent command)
+    #[command(hide = true)]
+    Sandbox {
+        /// Directory of the run
+        #[arg(long)]
+        run_dir: PathBuf,
+
+        /// Already inside the sandbox: forward the proxy port, then run the command
+        #[arg(long)]
+        inside: bool,
+
+        /// Shell command that starts the agent
+        command: String,
+    },
+    /// Remove an issue's worktree once its tmux session has ended (run by the tmux session-closed hook)
+    #[command(hide = true)]
+    Cleanup {
+        /// The issue branch (normally the issue ID)
+        branch: String,
+
+        /// Path to the worktree to remove
+        #[arg(long)]
+        worktree: PathBuf,
+
+        /// Name of the session that triggered the hook; cleanup is skipped if it is not this issue's session
+        #[arg(long)]
+        closed_session: Option<String>,
+    },
+
     /// Test creating a tmux session with Gemini (dry-run without git/beads)
     #[command(hide = true)]
     TestTmux,
@@ -84,10 +299,26 @@ fn main() -> Result<()> {
     }
 
     match cli.command {
-        Some(Commands::Start { id, model, agent, create_args }) => handle_start(id, model, agent, create_args, cli.verbose, backend),
+        Some(Commands::Start { id, model, agent, on, create_args }) => handle_start(id, model, agent, on, create_args, cli.verbose, backend),
+        Some(Commands::Revise { issue_id, model, agent }) => handle_revise(issue_id, model, agent, cli.verbose, backend),
+        Some(Commands::FixCi { issue_id, model, agent, watch, max_attempts }) => handle_fix_ci(issue_id, model, agent, watch, max_attempts, cli.verbose, backend),
+        Some(Commands::Compare { issue_id, test }) => handle_compare(issue_id, test, cli.verbose),
+        Some(Commands::Pick { issue_id, agent }) => handle_pick(issue_id, agent, cli.verbose, backend),
         Some(Commands::Unstart { issue_id }) => handle_unstart(issue_id, cli.verbose),
-        Some(Commands::Merge { issue_id }) => handle_merge(issue_id, cli.verbose, backend),
-        Some(Commands::Done) => handle_done(cli.verbose),
+        Some(Commands::Merge { issue_id, no_start }) => handle_merge(issue_id, no_start, cli.verbose, backend),
+        Some(Commands::Done) => handle_done(cli.verbose, backend),
+        Some(Commands::Doctor { agent }) => handle_doctor(agent, cli.verbose, backend),
+        Some(Commands::Logs { issue_id, list, run, raw, comment }) => handle_logs(issue_id, list, run, raw, comment, cli.verbose, backend),
+        Some(Commands::Say { issue_id, message, file }) => handle_say(issue_id, message, file, cli.verbose),
+        Some(Commands::Watch { interval }) => handle_watch(interval, cli.verbose, backend),
+        Some(Commands::Next { list }) => handle_next(list, cli.verbose, backend),
+        Some(Commands::RunQueue { interval, once, concurrency }) => handle_run_queue(interval, once, concurrency, cli.verbose, backend),
+        Some(Commands::Report { by, since, until, format }) => handle_report(by, since, until, format, cli.verbose, backend),
+        Some(Commands::Audit { range, all, format }) => handle_audit(range, all, format),
+        Some(Commands::Gc { yes }) => handle_gc(yes, cli.verbose, backend),
+        Some(Commands::RunAgent { run_dir, tail_lines, command }) => handle_run_agent(run_dir, tail_lines, command, cli.verbose, backend),
+        Some(Commands::Sandbox { run_dir, inside, command }) => handle_sandbox(run_dir, inside, command, cli.verbose),
+        Some(Commands::Cleanup { branch, worktree, closed_session }) => handle_cleanup(branch, worktree, closed_session, cli.verbose, backend),
         Some(Commands::TestTmux) => handle_test_tmux(cli.verbose, backend),
         None => handle_scan(cli.verbose),
     }
@@ -98,27 +329,93 @@ fn handle_test_tmux(verbose: bool, backend: IssueBackend) -> Result<()> {
     let session_name = "fuzemill-test";
     println!("Starting test tmux session '{}'...", session_name);
 
-    spawn_gemini_tmux(&current_dir, "test-issue", None, session_name, verbose, backend)
+    let run_dir = create_run_dir(&current_dir, "test-issue")?;
+    let prompt = agent_prompt("test-issue", agent_co_author("gemini")?, false, backend);
+    fs::write(run_dir.join("prompt.md"), prompt).context("Failed to write agent prompt")?;
+    let agent_cmd = agent_command("gemini", None, &run_dir, false, PermissionMode::Yolo)?;
+    SessionHost::Tmux.create(session_name, &current_dir, &wrap_agent_command(&agent_cmd, &run_dir, backend), verbose)?;
+    SessionHost::Tmux.attach(session_name, verbose)
 }
 
-fn handle_done(verbose: bool) -> Result<()> {
-    // Check if we are inside a tmux session
-    if let Ok(_) = env::var("TMUX") {
-        if verbose {
-            println!("Detected tmux session. Killing session...");
+fn handle_done(verbose: bool, backend: IssueBackend) -> Result<()> {
+    // Agents started by fuzemill find their run through this; the run records where the agent runs
+    let Some(run_dir) = env::var_os("FUZEMILL_RUN_DIR").map(PathBuf::from) else {
+        println!("Not inside a fuzemill session. 'fuzemill done' only works within a session started by fuzemill.");
+        return Ok(());
+    };
+    let current_dir = env::current_dir().context("Failed to get current directory")?;
+    let record = RunRecord::load(&run_dir).ok();
+    // A sandbox can't reach the session, nor change fuzemill's state
+    let sandboxed = env::var("FUZEMILL_SANDBOX").is_ok();
+    let host = record
+        .as_ref()
+        .and_then(|r| r.runner.host())
+        .filter(|h| *h != SessionHost::Foreground && !sandboxed);
+
+    // Run the checks and open the PR while the session is still there to report problems to
+    let mut branch = None;
+    if let Some(git_root) = find_git_root(&current_dir) {
+        let current_branch = get_current_branch(&git_root)?;
+        let issue_id = record.as_ref().map(|r| r.issue_id.clone()).unwrap_or_else(|| current_branch.clone());
+
+        run_hook(&git_root, "pre-done", &issue_id, &current_branch, &git_root, verbose)?;
+        run_hook(&git_root, "post-agent", &issue_id, &current_branch, &git_root, verbose)?;
+        if !verify_checks(&git_root, (!sandboxed).then_some(run_dir.as_path()), verbose)? {
+            bail!("Checks failed. Fix the failures above, commit, and run 'fuzemill done' again.");
         }
-        // We kill the session. This will detach the client and close the window.
-        Command::new("tmux")
-            .arg("kill-session")
-            .status()
-            .context("Failed to kill tmux session")?;
-    } else {
-        println!("Not inside a tmux session. 'fuzemill done' currently only works within a fuzemill-started tmux session.");
+        // A sandbox has no credentials to push with; the PR is opened once the agent is stopped
+        if !sandboxed
+            && let Err(e) = ensure_pr(&git_root, verbose, backend)
+        {
+            eprintln!("{}", format!("Failed to open the PR: {}", e).red());
+            bail!("Fix the problem above and run 'fuzemill done' again.");
+        }
+        run_hook(&git_root, "post-done", &issue_id, &current_branch, &git_root, verbose)?;
+        branch = Some(current_branch);
     }
-    Ok(())
+
+    // Killing the session also kills the run-agent wrapper, so record the end of the run here.
+    // Other runners record it themselves once the agent exits, and 'fuzemill sandbox' records
+    // it for a sandboxed agent when it finds the mark left in the sandbox's outbox.
+    if sandboxed {
+        let mark = sandbox_outbox(&run_dir).join("done");
+        fs::write(&mark, "").with_context(|| format!("Failed to write {}", mark.display()))?;
+    } else if let Ok(mut run) = RunRecord::load(&run_dir) {
+        if host.is_some() {
+            run.finish(&current_dir, None);
+        }
+        run.done = true;
+        if let Err(e) = run.save(&run_dir) {
+            eprintln!("Warning: Failed to record end of run: {}", e);
+        }
+    }
+
+    // Only the branch's own session is ever killed, whatever session 'done' happens to run in
+    let (Some(host), Some(branch)) = (host, branch) else {
+        if sandboxed {
+            println!("Done. The agent will be stopped and the PR opened outside the sandbox.");
+        } else {
+            println!("Done. Exit the agent to end the session.");
+        }
+        return Ok(());
+    };
+    let session_name = format!("fuzemill-{}", branch);
+
+    // tmux cleans up through its session-closed hook; the other hosts have none
+    if host != SessionHost::Tmux
+        && let Err(e) = schedule_cleanup(&current_dir, backend)
+    {
+        eprintln!("Warning: Failed to schedule the cleanup: {}", e);
+    }
+
+    if verbose {
+        println!("Killing {} session '{}'...", host.name(), session_name);
+    }
+    // We kill the session. This will detach the client and close the window.
+    host.kill(&session_name)
 }
 
-fn handle_merge(issue_id: String, verbose: bool, backend: IssueBackend) -> Result<()> {
+fn handle_merge(issue_id: String, no_start: bool, verbose: bool, backend: IssueBackend) -> Result<()> {
     let current_dir = env::current_dir().context("Failed to get current directory")?;
     let git_root = find_git_root(&current_dir).context("Not in a git repository")?;
     let (_, is_worktree) = get_git_common_dir(&git_root)?;
@@ -132,6 +429,8 @@ fn handle_merge(issue_id: String, verbose: bool, backend: IssueBackend) -> Resul
     let worktree_dir_name = format!("{}-{}", repo_dirname, issue_id);
     let worktree_path = git_root.parent().unwrap_or(Path::new(".")).join(&worktree_dir_name);
 
+    run_hook(&git_root, "pre-merge", &issue_id, &issue_id, &worktree_path, verbose)?;
+
     if worktree_path.exists() {
         if verbose {
             println!("Removing worktree at {} to release branch lock...", worktree_path.display());
@@ -148,15 +447,26 @@ fn handle_merge(issue_id: String, verbose: bool, backend: IssueBackend) -> Resul
         }
     }
 
+    // Issues stacked on this one are rebased from here once it is merged
+    let merged_tip = find_branch_ref(&git_root, &[format!("refs/heads/{}", issue_id), format!("refs/remotes/origin/{}", issue_id)])
+        .and_then(|r| git_rev_parse(&git_root, &r));
+
+    // Unsigned agent commits are squashed into one that GitHub signs for the person merging
+    let agent = list_runs(&git_root, &issue_id)?.pop().and_then(|d| RunRecord::load(&d).ok()).map(|r| r.agent);
+    let squash = agent.is_some_and(|a| load_config(&git_root).is_ok_and(|c| c.identity.for_agent(&a).signing == Signing::Merge));
+
+    // What was ready before the merge, so only the issues it unblocks are started afterwards
+    let ready_before = if no_start { None } else { load_config(&git_root).ok().and_then(|c| fetch_ready_issues(&git_root, &c.queue, false, backend).ok()) };
+
     if verbose {
-        println!("Merging PR for branch '{}'...", issue_id);
+        println!("{} PR for branch '{}'...", if squash { "Squash-merging" } else { "Merging" }, issue_id);
     }
 
     let status = Command::new("gh")
         .arg("pr")
         .arg("merge")
         .arg(&issue_id)
-        .arg("--merge")
+        .arg(if squash { "--squash" } else { "--merge" })
         .arg("--delete-branch")
         .status()
         .context("Failed to execute 'gh pr merge'")?;
@@ -179,15 +489,241 @@ fn handle_merge(issue_id: String, verbose: bool, backend: IssueBackend) -> Resul
     }
 
     println!("Successfully merged PR for {} and updated main.", issue_id);
+    set_issue_outcome(&git_root, &issue_id, "merged");
+
+    if let Err(e) = restack_dependents(&git_root, &issue_id, merged_tip.as_deref(), verbose) {
+        eprintln!("Warning: Failed to update the issues stacked on {}: {}", issue_id, e);
+    }
 
     // Close the issue
     if let Err(e) = close_issue(&git_root, &issue_id, verbose, backend) {
         eprintln!("Warning: Failed to close issue: {}", e);
     }
 
+    if let Some(ready_before) = ready_before
+        && let Err(e) = start_unblocked_issues(&git_root, &ready_before, verbose, backend)
+    {
+        eprintln!("Warning: Failed to start the issues unblocked by {}: {}", issue_id, e);
+    }
+
+    run_hook(&git_root, "post-merge", &issue_id, &issue_id, &worktree_path, verbose)
+}
+
+// Starts the queued issues that became ready since ready_before, as far as queue.concurrency
+// leaves free slots. Headless runs are left running in the background.
+fn start_unblocked_issues(git_root: &Path, ready_before: &[ReadyIssue], verbose: bool, backend: IssueBackend) -> Result<()> {
+    let config = load_config(git_root)?;
+    let mut unblocked = fetch_ready_issues(git_root, &config.queue, verbose, backend)?;
+    unblocked.retain(|i| !ready_before.iter().any(|b| b.id == i.id));
+    if unblocked.is_empty() {
+        return Ok(());
+    }
+
+    let runner = config.resolve_runner()?;
+    if runner == Runner::Foreground {
+        println!("Unblocked: {}. Start them with 'fuzemill next'.", unblocked.iter().map(|i| i.id.as_str()).collect::<Vec<_>>().join(", "));
+        return Ok(());
+    }
+    let running = match runner.host() {
+        Some(host) => host.list().iter().filter(|s| s.starts_with("fuzemill-")).count(),
+        // Headless runs are still running while their run has no end time
+        None => list_state_branches(git_root)?
+            .iter()
+            .filter_map(|b| list_runs(git_root, b).ok()?.pop())
+            .filter_map(|d| RunRecord::load(&d).ok())
+            .filter(|r| r.runner == Runner::Headless && r.ended_at.is_none())
+            .count(),
+    };
+    let free = config.queue.concurrency.saturating_sub(running);
+    if free < unblocked.len() {
+        println!("{} agents running; not starting {}.", running, unblocked[free..].iter().map(|i| i.id.as_str()).collect::<Vec<_>>().join(", "));
+    }
+    if free == 0 {
+        return Ok(());
+    }
+    preflight(git_root, &config.queue.agent, verbose, backend)?;
+
+    for issue in unblocked.into_iter().take(free) {
+        println!("{}", format!("Starting {} ({})...", issue.id, issue.title).green());
+        if let Err(e) = start_queued_issue(git_root, &issue.id, &config.queue.agent, runner, verbose, backend) {
+            eprintln!("Warning: Failed to start {}: {}", issue.id, e);
+        }
+    }
+    Ok(())
+}
+
+// Moves the open issues stacked on a merged issue onto the branch it was merged into: rebases
+// their branches, retargets their PRs, and records the new base
+fn restack_dependents(git_root: &Path, merged: &str, merged_tip: Option<&str>, verbose: bool) -> Result<()> {
+    let new_base = IssueRecord::load(git_root, merged)?.base;
+    let new_base_branch = match &new_base {
+        Some(base) => base.clone(),
+        None => default_branch(git_root)?,
+    };
+
+    for branch in list_state_branches(git_root)? {
+        let mut record = IssueRecord::load(git_root, &branch)?;
+        if record.base.as_deref() != Some(merged) || record.outcome.is_some() {
+            continue;
+        }
+
+        println!("Moving {} from {} onto {}...", branch, merged, new_base_branch);
+        match rebase_stacked_branch(git_root, &branch, &new_base_branch, merged_tip, verbose) {
+            Ok(true) => {}
+            Ok(false) => eprintln!(
+                "{}",
+                format!("Warning: {} conflicts with {} and was not rebased. Rebase it by hand, or run 'fuzemill revise {}'.", branch, new_base_branch, branch).yellow()
+            ),
+            Err(e) => eprintln!("Warning: Failed to rebase {}: {}", branch, e),
+        }
+
+        if record.pr_url.is_some() {
+            let status = Command::new("gh")
+                .arg("pr")
+                .arg("edit")
+                .arg(&branch)
+                .arg("--base")
+                .arg(&new_base_branch)
+                .current_dir(git_root)
+                .stdout(Stdio::null())
+                .status()
+                .context("Failed to execute 'gh pr edit'")?;
+            if !status.success() {
+                eprintln!("Warning: Failed to retarget the PR for {} to {}.", branch, new_base_branch);
+            }
+        }
+
+        record.base = new_base.clone();
+        record.save(git_root, &branch)?;
+    }
     Ok(())
 }
 
+// Rebases the branch's own commits (those after merged_tip) onto the new base and pushes it
+// if it was pushed before. Returns false if the rebase conflicts; the branch is left as it was.
+fn rebase_stacked_branch(git_root: &Path, branch: &str, onto: &str, merged_tip: Option<&str>, verbose: bool) -> Result<bool> {
+    let onto_ref = find_branch_ref(git_root, &[format!("refs/remotes/origin/{}", onto), format!("refs/heads/{}", onto)])
+        .with_context(|| format!("Branch {} not found", onto))?;
+    let session_name = format!("fuzemill-{}", branch);
+    if session_host(git_root, branch).is_alive(&session_name) {
+        bail!("its agent session is still running");
+    }
+
+    // Rebase in the issue's worktree, or in a temporary one if it is gone
+    let worktree = issue_worktree_path(git_root, branch)?;
+    let temporary = !worktree.exists();
+    let checkout = if temporary {
+        let path = env::temp_dir().join(format!("fuzemill-restack-{}-{}", branch, std::process::id()));
+        let status = Command::new("git")
+            .arg("worktree")
+            .arg("add")
+            .arg("--quiet")
+            .arg(&path)
+            .arg(branch)
+            .current_dir(git_root)
+            .status()
+            .context("Failed to execute git worktree add")?;
+        if !status.success() {
+            bail!("git worktree add failed");
+        }
+        path
+    } else {
+        worktree
+    };
+
+    let result = rebase_in(&checkout, branch, &onto_ref, merged_tip, verbose);
+    if temporary {
+        let _ = Command::new("git")
+            .arg("worktree")
+            .arg("remove")
+            .arg("--force")
+            .arg(&checkout)
+            .current_dir(git_root)
+            .status();
+    }
+    result
+}
+
+fn rebase_in(checkout: &Path, branch: &str, onto_ref: &str, merged_tip: Option<&str>, verbose: bool) -> Result<bool> {
+    let dirty = Command::new("git")
+        .arg("status")
+        .arg("--porcelain")
+        .arg("--untracked-files=no")
+        .current_dir(checkout)
+        .output()
+        .context("Failed to execute 'git status'")?;
+    if !dirty.stdout.is_empty() {
+        bail!("{} has uncommitted changes", checkout.display());
+    }
+
+    if verbose {
+        println!("Rebasing {} onto {}...", branch, onto_ref);
+    }
+    // With --onto, commits of the merged issue are dropped even if they were squashed
+    let mut rebase = Command::new("git");
+    rebase.arg("rebase").arg("--quiet");
+    match merged_tip {
+        Some(tip) => rebase.arg("--onto").arg(onto_ref).arg(tip),
+        None => rebase.arg(onto_ref),
+    };
+    let output = rebase.current_dir(checkout).output().context("Failed to execute 'git rebase'")?;
+    if !output.status.success() {
+        let _ = Command::new("git")
+            .arg("rebase")
+            .arg("--abort")
+            .current_dir(checkout)
+            .output();
+        return Ok(false);
+    }
+
+    if find_branch_ref(checkout, &[format!("refs/remotes/origin/{}", branch)]).is_some() {
+        let output = Command::new("git")
+            .arg("push")
+            .arg("--force-with-lease")
+            .arg("origin")
+            .arg(branch)
+            .current_dir(checkout)
+            .output()
+            .context("Failed to execute 'git push'")?;
+        if !output.status.success() {
+            bail!("git push failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+        }
+    }
+    Ok(true)
+}
+
+// The remote's default branch, e.g. "main"
+fn default_branch(git_root: &Path) -> Result<String> {
+    let output = Command::new("git")
+        .arg("symbolic-ref")
+        .arg("--short")
+        .arg("refs/remotes/origin/HEAD")
+        .current_dir(git_root)
+        .output()
+        .context("Failed to execute 'git symbolic-ref'")?;
+    if output.status.success() {
+        let head = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        return Ok(head.strip_prefix("origin/").unwrap_or(&head).to_string());
+    }
+
+    // origin/HEAD is only set for clones
+    let output = Command::new("gh")
+        .arg("repo")
+        .arg("view")
+        .arg("--json")
+        .arg("defaultBranchRef")
+        .arg("--jq")
+        .arg(".defaultBranchRef.name")
+        .current_dir(git_root)
+        .output()
+        .context("Failed to execute 'gh repo view'")?;
+    let name = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if !output.status.success() || name.is_empty() {
+        bail!("Failed to find the default branch of origin");
+    }
+    Ok(name)
+}
+
 fn close_issue(cwd: &Path, issue_id: &str, verbose: bool, backend: IssueBackend) -> Result<()> {
     match backend {
         IssueBackend::Beads => close_issue_beads(cwd, issue_id, verbose),
@@ -262,10 +798,42 @@ fn handle_scan(verbose: bool) -> Result<()> {
     Ok(())
 }
 
-fn handle_start(id: Option<String>, model: Option<String>, agent: String, create_args: Vec<String>, verbose: bool, backend: IssueBackend) -> Result<()> {
+fn handle_start(id: Option<String>, model: Option<String>, agent: Vec<String>, on: Option<String>, create_args: Vec<String>, verbose: bool, backend: IssueBackend) -> Result<()> {
     let current_dir = env::current_dir().context("Failed to get current directory")?;
     let git_root = find_git_root(&current_dir).context("Not in a git repository")?;
 
+    // Several agents race on the same issue
+    let mut agents: Vec<String> = Vec::new();
+    for a in agent {
+        if !agents.contains(&a) {
+            agents.push(a);
+        }
+    }
+    if agents.len() > 1 && model.is_some() {
+        bail!("--model can't be combined with several --agent values; each agent uses its default model.");
+    }
+    // The candidates of a race run side by side, which needs a session per agent
+    let config = load_config(&git_root)?;
+    let runner = config.resolve_runner()?;
+    if agents.len() > 1 && matches!(runner.host(), None | Some(SessionHost::Foreground)) {
+        bail!("Racing several agents needs a terminal multiplexer runner (tmux, zellij or screen).");
+    }
+
+    for agent in &agents {
+        preflight(&git_root, agent, verbose, backend)?;
+    }
+
+    // The branch the issue is stacked on, and where to start from
+    let on = match on {
+        Some(base) => {
+            let start_point = find_branch_ref(&git_root, &[format!("refs/heads/{}", base), format!("refs/remotes/origin/{}", base)])
+                .with_context(|| format!("Issue {} has no branch to stack on. Start it first.", base))?;
+            Some((base, start_point))
+        }
+        None => None,
+    };
+    let on = on.as_ref().map(|(base, start_point)| (base.as_str(), start_point.as_str()));
+
     let issue_id = if let Some(provided_id) = id {
         check_issue_exists(&provided_id, &git_root, verbose, backend)?;
         provided_id
@@ -278,6 +846,15 @@ fn handle_start(id: Option<String>, model: Option<String>, agent: String, create
         bail!("Please provide an issue ID via --id or arguments to create a new issue.");
     };
 
+    if on.is_some_and(|(base, _)| base == issue_id) {
+        bail!("An issue can't be stacked on itself.");
+    }
+
+    if agents.len() > 1 {
+        return start_race(&git_root, &config, &issue_id, &agents, on, verbose, backend);
+    }
+    let agent = agents.remove(0);
+
     // Determine the main repo name to use for prefixing
     let (main_repo_path, is_worktree) = get_git_common_dir(&git_root)?;
 
@@ -303,39 +880,11 @@ fn handle_start(id: Option<String>, model: Option<String>, agent: String, create
     let new_dir_name = format!("{}-{}", repo_name, issue_id);
     let new_worktree_path = base_parent.join(&new_dir_name);
 
-    if new_worktree_path.exists() {
-        println!("Worktree directory already exists: {}", new_worktree_path.display());
-        println!("Switching context...");
-    } else {
-        if verbose {
-            println!("Creating worktree at: {}", new_worktree_path.display());
-        }
-
-        // git worktree add -b <issue_id> <path>
-        let status = Command::new("git")
-            .arg("worktree")
-            .arg("add")
-            .arg("-b")
-            .arg(&issue_id)
-            .arg(&new_worktree_path)
-            .status()
-            .context("Failed to execute git worktree add")?;
-
-        if !status.success() {
-            bail!("git worktree add failed");
-        }
-    }
-
-    // Run direnv allow if .envrc exists
-    if new_worktree_path.join(".envrc").exists() {
-        if verbose {
-            println!("Detected .envrc, running 'direnv allow'...");
-        }
-        let _ = Command::new("direnv")
-            .arg("allow")
-            .current_dir(&new_worktree_path)
-            .status();
-    }
+    run_hook(&git_root, "pre-start", &issue_id, &issue_id, &new_worktree_path, verbose)?;
+    create_worktree(&issue_id, &new_worktree_path, on.map(|(_, start_point)| start_point), verbose)?;
+    record_stack_base(&git_root, &issue_id, on)?;
+    bootstrap_worktree(&new_worktree_path, verbose)?;
+    run_hook(&git_root, "post-worktree", &issue_id, &issue_id, &new_worktree_path, verbose)?;
 
     // Launch AI session
     println!("Launching {} session in {}", agent, new_worktree_path.display().to_string().green());
@@ -345,444 +894,6604 @@ fn handle_start(id: Option<String>, model: Option<String>, agent: String, create
         eprintln!("Warning: Failed to set issue status to 'hooked': {}", e);
     }
 
-    let session_name = format!("fuzemill-{}", issue_id);
-    match agent.as_str() {
-        "gemini" => spawn_gemini_tmux(&new_worktree_path, &issue_id, model, &session_name, verbose, backend)?,
-        "claude" => spawn_claude_tmux(&new_worktree_path, &issue_id, model, &session_name, verbose, backend)?,
-        _ => bail!("Unknown agent '{}'. Use 'claude' or 'gemini'.", agent),
-    }
+    let session = AgentSession {
+        prompt: agent_prompt(&issue_id, agent_co_author(&agent)?, config.pr.create, backend),
+        branch: issue_id.clone(),
+        issue_id,
+        worktree: new_worktree_path,
+        agent,
+        model,
+        launch_hook: Some("post-start"),
+    };
+    run_agent_session(&git_root, &session, verbose, backend)
+}
 
-    // Update status to in_progress
-    if let Err(e) = update_issue_status(&git_root, &issue_id, "in_progress", verbose, backend) {
-        eprintln!("Warning: Failed to set issue status to 'in_progress': {}", e);
+// Creates the worktree with a new branch, or reuses it if the directory is already there
+fn create_worktree(branch: &str, worktree_path: &Path, start_point: Option<&str>, verbose: bool) -> Result<()> {
+    if worktree_path.exists() {
+        println!("Worktree directory already exists: {}", worktree_path.display());
+        println!("Switching context...");
+        return Ok(());
     }
 
-    // Cleanup worktree
     if verbose {
-        println!("Cleaning up worktree at {}...", new_worktree_path.display());
+        println!("Creating worktree at: {}", worktree_path.display());
     }
+
+    // git worktree add -b <branch> <path> [<start-point>]
     let status = Command::new("git")
         .arg("worktree")
-        .arg("remove")
-        .arg(&new_worktree_path)
+        .arg("add")
+        .arg("-b")
+        .arg(branch)
+        .arg(worktree_path)
+        .args(start_point)
         .status()
-        .context("Failed to execute git worktree remove")?;
+        .context("Failed to execute git worktree add")?;
 
     if !status.success() {
-        eprintln!("Warning: Failed to remove worktree at {}", new_worktree_path.display());
+        bail!("git worktree add failed");
     }
 
     Ok(())
 }
 
-fn update_issue_status(cwd: &Path, issue_id: &str, status: &str, verbose: bool, backend: IssueBackend) -> Result<()> {
-    match backend {
-        IssueBackend::Beads => update_issue_status_beads(cwd, issue_id, status, verbose),
-        IssueBackend::GitHub => update_issue_status_github(cwd, issue_id, status, verbose),
+// Prepares a fresh worktree for the agent: brings over untracked files from the main
+// repository, checks out submodules, and runs the configured setup commands.
+fn bootstrap_worktree(worktree_path: &Path, verbose: bool) -> Result<()> {
+    let config = load_config(worktree_path)?.bootstrap;
+    let (main_repo_path, _) = get_git_common_dir(worktree_path)?;
+
+    for path in &config.copy {
+        let path = path.trim_end_matches('/');
+        let (src, dst) = (main_repo_path.join(path), worktree_path.join(path));
+        if !src.exists() {
+            eprintln!("Warning: {} does not exist in the main repository, not copying it.", path);
+            continue;
+        }
+        if dst.symlink_metadata().is_ok() {
+            continue;
+        }
+        if verbose {
+            println!("Copying {}...", path);
+        }
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        let status = Command::new("cp")
+            .arg("-a")
+            .arg(&src)
+            .arg(&dst)
+            .status()
+            .context("Failed to execute 'cp'")?;
+        if !status.success() {
+            bail!("Failed to copy {} into the worktree", path);
+        }
     }
-}
 
-fn update_issue_status_beads(cwd: &Path, issue_id: &str, status: &str, verbose: bool) -> Result<()> {
-    if verbose {
-        println!("Updating bead {} status to '{}'...", issue_id, status);
+    for path in &config.symlink {
+        let path = path.trim_end_matches('/');
+        let (src, dst) = (main_repo_path.join(path), worktree_path.join(path));
+        if !src.exists() {
+            eprintln!("Warning: {} does not exist in the main repository, not linking it.", path);
+            continue;
+        }
+        if dst.symlink_metadata().is_ok() {
+            continue;
+        }
+        if verbose {
+            println!("Linking {}...", path);
+        }
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        std::os::unix::fs::symlink(&src, &dst).with_context(|| format!("Failed to link {} into the worktree", path))?;
     }
 
-    let output = Command::new("bd")
-        .arg("update")
-        .arg(issue_id)
-        .arg("--status")
-        .arg(status)
-        .current_dir(cwd)
-        .output()
-        .context("Failed to execute 'bd update'")?;
+    // Keep the copies out of 'git status', so they don't stop the worktree from being removed
+    if !config.copy.is_empty() || !config.symlink.is_empty() {
+        let paths: Vec<&String> = config.copy.iter().chain(&config.symlink).collect();
+        exclude_paths(&main_repo_path, &paths)?;
+    }
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        bail!("bd update failed: {}", stderr.trim());
+    if config.submodules {
+        if verbose {
+            println!("Checking out submodules...");
+        }
+        let status = Command::new("git")
+            .arg("submodule")
+            .arg("update")
+            .arg("--init")
+            .arg("--recursive")
+            .current_dir(worktree_path)
+            .status()
+            .context("Failed to execute 'git submodule update'")?;
+        if !status.success() {
+            bail!("git submodule update failed");
+        }
+    }
+
+    // Run direnv allow if .envrc exists
+    if worktree_path.join(".envrc").exists() {
+        if verbose {
+            println!("Detected .envrc, running 'direnv allow'...");
+        }
+        let _ = Command::new("direnv")
+            .arg("allow")
+            .current_dir(worktree_path)
+            .status();
+    }
+
+    for cmd in &config.run {
+        println!("Running '{}'...", cmd);
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(cmd)
+            .current_dir(worktree_path)
+            .status()
+            .with_context(|| format!("Failed to execute '{}'", cmd))?;
+        if !status.success() {
+            bail!("Bootstrap command '{}' failed", cmd);
+        }
     }
+
     Ok(())
 }
 
-fn update_issue_status_github(cwd: &Path, issue_id: &str, status: &str, verbose: bool) -> Result<()> {
-    // GitHub Issues don't have custom statuses like beads.
-    // We use labels to track status (e.g., "status:hooked", "status:in_progress")
-    let label = format!("status:{}", status);
+// Records the issue a branch is stacked on, for its PR and for restacking after a merge
+fn record_stack_base(git_root: &Path, branch: &str, on: Option<(&str, &str)>) -> Result<()> {
+    let Some((base, _)) = on else {
+        return Ok(());
+    };
+    let mut record = IssueRecord::load(git_root, branch)?;
+    record.base = Some(base.to_string());
+    record.save(git_root, branch)
+}
 
-    if verbose {
-        println!("Updating GitHub issue #{} status to '{}' via label...", issue_id, label);
+// The first of the refs that exists
+fn find_branch_ref(git_root: &Path, refs: &[String]) -> Option<String> {
+    refs.iter()
+        .find(|r| {
+            Command::new("git")
+                .arg("rev-parse")
+                .arg("--verify")
+                .arg("--quiet")
+                .arg(format!("{}^{{commit}}", r))
+                .current_dir(git_root)
+                .output()
+                .is_ok_and(|o| o.status.success())
+        })
+        .cloned()
+}
+
+// Adds paths to the repository's info/exclude, which is shared by all worktrees
+fn exclude_paths(main_repo_path: &Path, paths: &[&String]) -> Result<()> {
+    let path = main_repo_path.join(".git").join("info").join("exclude");
+    let existing = fs::read_to_string(&path).unwrap_or_default();
+
+    let mut missing = String::new();
+    for p in paths {
+        let pattern = format!("/{}", p.trim_start_matches('/').trim_end_matches('/'));
+        if !existing.lines().any(|l| l == pattern) {
+            missing.push_str(&pattern);
+            missing.push('\n');
+        }
+    }
+    if missing.is_empty() {
+        return Ok(());
     }
 
-    // Add the status label (best effort - won't fail if label doesn't exist)
-    let output = Command::new("gh")
-        .arg("issue")
-        .arg("edit")
-        .arg(issue_id)
-        .arg("--add-label")
-        .arg(&label)
-        .current_dir(cwd)
-        .output()
-        .context("Failed to execute 'gh issue edit'")?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    let mut content = existing;
+    if !content.is_empty() && !content.ends_with('\n') {
+        content.push('\n');
+    }
+    content.push_str(&missing);
+    fs::write(&path, content).with_context(|| format!("Failed to write {}", path.display()))
+}
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        // Don't fail if label doesn't exist - status labels are optional
+// Data passed to hooks as JSON on stdin
+#[derive(Serialize)]
+struct HookPayload<'a> {
+    event: &'a str,
+    issue: &'a str,
+    branch: &'a str,
+    worktree: &'a Path,
+    agent: Option<String>,
+    pr: Option<String>,
+}
+
+// Runs the hooks for an event: the command from [hooks] in the config, then the script
+// .fuzemill/hooks/<event> in the main repository. Both get the payload on stdin and the issue,
+// branch and worktree in FUZEMILL_* environment variables. A failing pre-* hook vetoes the
+// operation; a failing post-* hook only warns.
+fn run_hook(git_root: &Path, event: &str, issue_id: &str, branch: &str, worktree_path: &Path, verbose: bool) -> Result<()> {
+    let config = load_config(git_root)?;
+    let (main_repo_path, _) = get_git_common_dir(git_root)?;
+
+    let mut hooks = Vec::new();
+    if let Some(cmd) = config.hooks.command(event) {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(cmd);
+        hooks.push((cmd.to_string(), command));
+    }
+    let script = main_repo_path.join(".fuzemill").join("hooks").join(event);
+    if script.is_file() {
+        hooks.push((script.display().to_string(), Command::new(&script)));
+    }
+    if hooks.is_empty() {
+        return Ok(());
+    }
+
+    let payload = HookPayload {
+        event,
+        issue: issue_id,
+        branch,
+        worktree: worktree_path,
+        agent: list_runs(git_root, branch)?
+            .last()
+            .and_then(|r| RunRecord::load(r).ok())
+            .map(|r| r.agent),
+        pr: IssueRecord::load(git_root, branch)?.pr_url,
+    };
+    let payload = serde_json::to_string(&payload)?;
+
+    // The worktree does not exist yet before start, or any more after unstart
+    let cwd = if worktree_path.exists() {
+        worktree_path.to_path_buf()
+    } else {
+        main_repo_path
+    };
+
+    for (name, mut command) in hooks {
         if verbose {
-            eprintln!("Warning: Could not add label '{}': {}", label, stderr.trim());
+            println!("Running {} hook '{}'...", event, name);
+        }
+        command
+            .current_dir(&cwd)
+            .env("FUZEMILL_EVENT", event)
+            .env("FUZEMILL_ISSUE", issue_id)
+            .env("FUZEMILL_BRANCH", branch)
+            .env("FUZEMILL_WORKTREE", worktree_path)
+            .stdin(Stdio::piped());
+        let mut child = command.spawn().with_context(|| format!("Failed to execute {} hook '{}'", event, name))?;
+        // Hooks don't have to read the payload
+        if let Some(mut stdin) = child.stdin.take() {
+            let _ = stdin.write_all(payload.as_bytes());
+        }
+        let status = child.wait().with_context(|| format!("Failed to execute {} hook '{}'", event, name))?;
+
+        if !status.success() {
+            if event.starts_with("pre-") {
+                bail!("The {} hook '{}' failed; aborting.", event, name);
+            }
+            eprintln!("Warning: The {} hook '{}' failed.", event, name);
         }
     }
     Ok(())
 }
 
-fn spawn_gemini_tmux(path: &Path, issue_id: &str, model: Option<String>, session_name: &str, verbose: bool, backend: IssueBackend) -> Result<()> {
-    let current_exe = env::current_exe().unwrap_or_else(|_| PathBuf::from("fuzemill"));
-    let done_cmd = format!("{} done", current_exe.display());
+struct AgentSession {
+    issue_id: String,
+    // Branch the agent works on; it names the session, worktree and state dir. This is the
+    // issue ID, except for race candidates (<issue>-<agent>).
+    branch: String,
+    worktree: PathBuf,
+    agent: String,
+    model: Option<String>,
+    prompt: String,
+    // Hook event to run once the agent has been launched
+    launch_hook: Option<&'static str>,
+}
 
-    let issue_view_cmd = match backend {
-        IssueBackend::Beads => format!("bd show {}", issue_id),
-        IssueBackend::GitHub => format!("gh issue view {}", issue_id),
+// Runs the agent for an issue with the configured runner and finishes up once it ends
+fn run_agent_session(git_root: &Path, session: &AgentSession, verbose: bool, backend: IssueBackend) -> Result<()> {
+    let config = load_config(git_root)?;
+    let runner = config.resolve_runner()?;
+    let Some(host) = runner.host() else {
+        return run_headless(git_root, session, config.runner.timeout_minutes, verbose, backend);
     };
+    let session_name = format!("fuzemill-{}", session.branch);
+    let run_dir = launch_agent_session(git_root, runner, host, session, &session_name, verbose, backend)?;
+    attach_agent_session(git_root, host, session, &session_name, &run_dir, verbose, backend)
+}
 
-    let prompt = format!(
-        "You are working on issue {}. Please call '{}' to get the details of the issue. Your task is to fix this issue, commit the changes, push, and open a PR. When committing, please include a descriptive message and add 'Co-authored-by: Gemini <gemini@google.com>' to the commit message. When you are finished, run '{}' to close the session.",
-        issue_id, issue_view_cmd, done_cmd
-    );
+// Attaches to a launched agent session and finishes up once it ends
+fn attach_agent_session(git_root: &Path, host: SessionHost, session: &AgentSession, session_name: &str, run_dir: &Path, verbose: bool, backend: IssueBackend) -> Result<()> {
+    let issue_id = &session.issue_id;
+    host.attach(session_name, verbose)?;
 
-    // Construct the command to run inside tmux
-    let mut gemini_cmd = String::from("gemini --yolo --prompt-interactive");
-    if let Some(m) = model {
-        gemini_cmd.push_str(&format!(" --model {}", m));
+    // A crashed agent (bad model name, expired auth, ...) never got to work on the issue
+    match read_agent_exit_code(run_dir) {
+        Some(code) if code != 0 => {
+            print_agent_failure(run_dir, code);
+        }
+        _ => {
+            if let Err(e) = update_issue_status(git_root, issue_id, "in_progress", verbose, backend) {
+                eprintln!("Warning: Failed to set issue status to 'in_progress': {}", e);
+            }
+        }
     }
-    // We need to quote the prompt properly for the shell inside tmux
-    // A simple escaping for single quotes might be enough if we wrap prompt in single quotes
-    let escaped_prompt = prompt.replace("'", "'\\''");
-    gemini_cmd.push_str(&format!(" '{}'", escaped_prompt));
 
-    if verbose {
-        println!("Creating tmux session '{}'...", session_name);
+    // Attach also returns when the user detaches (Ctrl-b d). The agent is still
+    // working in the worktree then, so leave it alone.
+    if host.is_alive(session_name) {
+        println!("Session '{}' is still running. Reattach with:", session_name);
+        println!("  {}", host.attach_command(session_name));
+        return Ok(());
     }
 
-    // tmux new-session -d -s <name> -c <path> <command>
-    let status = Command::new("tmux")
-        .arg("new-session")
-        .arg("-d")
-        .arg("-s")
-        .arg(session_name)
-        .arg("-c")
-        .arg(path)
-        .arg(&gemini_cmd)
-        .status()
-        .context("Failed to create tmux session")?;
+    finish_session(&session.branch, &session.worktree, session_name, verbose, backend)
+}
 
-    if !status.success() {
-        bail!("Failed to create tmux session. Is tmux installed?");
+// Records a new run and starts the agent in a detached session. Returns the run directory.
+// In the foreground the agent runs to completion before this returns.
+fn launch_agent_session(git_root: &Path, runner: Runner, host: SessionHost, session: &AgentSession, session_name: &str, verbose: bool, backend: IssueBackend) -> Result<PathBuf> {
+    let run_dir = prepare_run(git_root, session, runner, verbose, backend)?;
+    let permissions = load_config(git_root)?.permissions.mode;
+    let agent_cmd = agent_command(&session.agent, session.model.as_deref(), &run_dir, false, permissions)?;
+    let agent_cmd = sandbox_command(git_root, agent_cmd, &run_dir)?;
+    // A foreground agent has exited by the time it returns, so its hook runs before the start
+    let foreground = host == SessionHost::Foreground;
+    if foreground && let Some(event) = session.launch_hook {
+        run_hook(git_root, event, &session.issue_id, &session.branch, &session.worktree, verbose)?;
     }
+    host.create(session_name, &session.worktree, &wrap_agent_command(&agent_cmd, &run_dir, backend), verbose)?;
 
-    if verbose {
-        println!("Attaching to tmux session...");
+    // Register the cleanup hook before attaching, so the worktree is still cleaned up
+    // if this process goes away (e.g. the terminal is closed) while the session keeps running.
+    if host == SessionHost::Tmux
+        && let Err(e) = install_cleanup_hook(&session.branch, &session.worktree, session_name, verbose, backend)
+    {
+        eprintln!("Warning: Failed to install tmux cleanup hook: {}", e);
     }
 
-    // tmux attach -t <name>
-    let _status = Command::new("tmux")
-        .arg("attach")
-        .arg("-t")
-        .arg(session_name)
-        .status()
-        .context("Failed to attach to tmux session")?;
-    
-    // If attach fails (e.g. user detaches or session dies), we continue.
-    // The start logic will cleanup after this returns.
-
-    Ok(())
+    if !foreground && let Some(event) = session.launch_hook {
+        run_hook(git_root, event, &session.issue_id, &session.branch, &session.worktree, verbose)?;
+    }
+    Ok(run_dir)
 }
 
-fn spawn_claude_tmux(path: &Path, issue_id: &str, model: Option<String>, session_name: &str, verbose: bool, backend: IssueBackend) -> Result<()> {
-    let current_exe = env::current_exe().unwrap_or_else(|_| PathBuf::from("fuzemill"));
-    let done_cmd = format!("{} done", current_exe.display());
+// Records a new run and writes the agent's prompt to its directory. Returns the run directory.
+fn prepare_run(git_root: &Path, session: &AgentSession, runner: Runner, verbose: bool, backend: IssueBackend) -> Result<PathBuf> {
+    let config = load_config(git_root)?;
+    write_agent_permissions(&config.permissions, session, backend)?;
+    apply_agent_identity(config.identity.for_agent(&session.agent), &session.worktree)?;
 
-    let issue_view_cmd = match backend {
-        IssueBackend::Beads => format!("bd show {}", issue_id),
-        IssueBackend::GitHub => format!("gh issue view {}", issue_id),
+    let run_dir = create_run_dir(git_root, &session.branch)?;
+    let run = RunRecord {
+        issue_id: session.issue_id.clone(),
+        agent: session.agent.clone(),
+        model: session.model.clone(),
+        runner,
+        started_at: utc_timestamp(unix_now()),
+        base_commit: git_head(&session.worktree),
+        ..Default::default()
     };
+    run.save(&run_dir)?;
 
-    let prompt = format!(
-        "You are working on issue {}. Please call '{}' to get the details of the issue. Your task is to fix this issue, commit the changes, push, and open a PR. When committing, please include a descriptive message and add 'Co-authored-by: Claude <noreply@anthropic.com>' to the commit message. When you are finished, run '{}' to close the session.",
-        issue_id, issue_view_cmd, done_cmd
-    );
+    // After the run is recorded, so the hook sees the agent; a vetoed run is dropped again
+    if let Err(e) = run_hook(git_root, "pre-agent", &session.issue_id, &session.branch, &session.worktree, verbose) {
+        let _ = fs::remove_dir_all(&run_dir);
+        return Err(e);
+    }
 
-    let mut claude_cmd = String::from("claude --dangerously-skip-permissions");
-    if let Some(m) = model {
-        claude_cmd.push_str(&format!(" --model {}", m));
+    if config.instructions.enabled
+        && let Err(e) = write_agent_instructions(&config, session, backend)
+    {
+        eprintln!("Warning: Failed to write agent instructions: {}", e);
+    }
+    if let Err(e) = install_commit_hooks(git_root, session) {
+        eprintln!("Warning: Failed to install the commit trailer hook: {}", e);
     }
-    let escaped_prompt = prompt.replace("'", "'\\''");
-    claude_cmd.push_str(&format!(" '{}'", escaped_prompt));
 
-    if verbose {
-        println!("Creating tmux session '{}'...", session_name);
+    let mut prompt = session.prompt.clone();
+    let checks = config.checks;
+    if !checks.is_empty() {
+        let commands: Vec<String> = checks.iter().map(|c| format!("'{}'", c.run)).collect();
+        prompt.push_str(&format!(
+            "\n\nBefore closing the session, 'done' runs these checks: {}. If any of them fails, it prints the output and the session stays open; fix the failures, commit, and run 'done' again.",
+            commands.join(", ")
+        ));
     }
+    fs::write(run_dir.join("prompt.md"), prompt).context("Failed to write agent prompt")?;
 
-    let status = Command::new("tmux")
-        .arg("new-session")
-        .arg("-d")
-        .arg("-s")
-        .arg(session_name)
+    Ok(run_dir)
+}
+
+// Runs the agent's non-interactive mode as a child process with its output in agent.log,
+// then runs the checks and opens the PR like a finished tmux session. The result is
+// printed as JSON and saved as result.json; a failed run makes the command fail.
+fn run_headless(git_root: &Path, session: &AgentSession, timeout_minutes: Option<u64>, verbose: bool, backend: IssueBackend) -> Result<()> {
+    let run_dir = prepare_run(git_root, session, Runner::Headless, verbose, backend)?;
+    let permissions = load_config(git_root)?.permissions.mode;
+    let agent_cmd = agent_command(&session.agent, session.model.as_deref(), &run_dir, true, permissions)?;
+    let agent_cmd = sandbox_command(git_root, agent_cmd, &run_dir)?;
+    let log_path = run_dir.join("agent.log");
+    let log = fs::File::create(&log_path).with_context(|| format!("Failed to create {}", log_path.display()))?;
+
+    println!("Running {} headless in {} (output in {})", session.agent, session.worktree.display(), log_path.display());
+    let started = Instant::now();
+    // exec, so a timeout kills the agent itself and not just the shell
+    let mut child = Command::new("sh")
         .arg("-c")
-        .arg(path)
-        .arg(&claude_cmd)
-        .status()
-        .context("Failed to create tmux session")?;
+        .arg(format!("exec {}", agent_cmd))
+        .current_dir(&session.worktree)
+        .env("FUZEMILL_RUN_DIR", &run_dir)
+        .env_remove("TMUX")
+        .env_remove("TMUX_PANE")
+        .env_remove("STY")
+        .env_remove("ZELLIJ")
+        .env_remove("ZELLIJ_SESSION_NAME")
+        .env_remove("ZELLIJ_PANE_ID")
+        .stdin(Stdio::null())
+        .stdout(log.try_clone().context("Failed to open the agent log")?)
+        .stderr(log)
+        .spawn()
+        .context("Failed to start the agent")?;
 
-    if !status.success() {
-        bail!("Failed to create tmux session. Is tmux installed?");
+    if let Some(event) = session.launch_hook {
+        run_hook(git_root, event, &session.issue_id, &session.branch, &session.worktree, verbose)?;
     }
 
-    if verbose {
-        println!("Attaching to tmux session...");
+    let timeout = timeout_minutes.map(|m| Duration::from_secs(m * 60));
+    let mut timed_out = false;
+    let status = loop {
+        if let Some(status) = child.try_wait().context("Failed to wait for the agent")? {
+            break status;
+        }
+        if timeout.is_some_and(|t| started.elapsed() >= t) {
+            eprintln!("Warning: The agent did not finish within {} minutes; stopping it.", timeout_minutes.unwrap_or(0));
+            let _ = child.kill();
+            timed_out = true;
+            break child.wait().context("Failed to wait for the agent")?;
+        }
+        thread::sleep(Duration::from_millis(500));
+    };
+
+    let exit_code = status.code().or_else(|| status.signal().map(|s| 128 + s)).unwrap_or(1);
+    let mut run = RunRecord::load(&run_dir)?;
+    run.finish(&session.worktree, Some(exit_code));
+    if timed_out {
+        run.limit = Some(format!("timed out after {} minutes", timeout_minutes.unwrap_or(0)));
+    }
+    if let Ok(output) = fs::read_to_string(run_dir.join("agent.log")) {
+        run.usage = headless_usage(&output);
     }
+    run.save(&run_dir)?;
 
-    let _status = Command::new("tmux")
-        .arg("attach")
-        .arg("-t")
-        .arg(session_name)
-        .status()
-        .context("Failed to attach to tmux session")?;
+    if exit_code != 0 {
+        print_agent_failure(&run_dir, exit_code);
+    } else if let Err(e) = update_issue_status(git_root, &session.issue_id, "in_progress", verbose, backend) {
+        eprintln!("Warning: Failed to set issue status to 'in_progress': {}", e);
+    }
+
+    // Record the results before the worktree (and with it the state) may be cleaned up
+    let session_name = format!("fuzemill-{}", session.branch);
+    finish_session(&session.branch, &session.worktree, &session_name, verbose, backend)?;
 
+    let run = RunRecord::load(&run_dir)?;
+    let checks_passed = (!run.checks.is_empty()).then(|| run.checks.iter().all(|c| c.passed()));
+    let result = serde_json::json!({
+        "issue": session.issue_id,
+        "branch": session.branch,
+        "agent": run.agent,
+        "model": run.model,
+        "exit_code": exit_code,
+        "timed_out": timed_out,
+        "duration_secs": started.elapsed().as_secs(),
+        "commits": run.commit_range(),
+        "checks_passed": checks_passed,
+        "pr": IssueRecord::load(git_root, &session.branch)?.pr_url,
+        "usage": run.usage,
+        "log": log_path,
+    });
+    let result = serde_json::to_string_pretty(&result)?;
+    fs::write(run_dir.join("result.json"), &result).context("Failed to write result.json")?;
+    println!("{}", result);
+
+    if exit_code != 0 || checks_passed == Some(false) {
+        bail!("The headless run for {} failed.", session.branch);
+    }
     Ok(())
 }
 
-fn create_new_issue(args: &[String], cwd: &Path, backend: IssueBackend) -> Result<String> {
-    match backend {
-        IssueBackend::Beads => create_new_issue_beads(args, cwd),
-        IssueBackend::GitHub => create_new_issue_github(args, cwd),
+// Claude's stream-json output ends with a result line that has the usage and cost of the run
+fn headless_usage(output: &str) -> Option<Usage> {
+    let result = output
+        .lines()
+        .rev()
+        .filter_map(|l| serde_json::from_str::<serde_json::Value>(l).ok())
+        .find(|v| v["type"] == "result")?;
+    let usage = &result["usage"];
+    let tokens = |key: &str| usage[key].as_u64().unwrap_or(0);
+    Some(Usage {
+        input_tokens: tokens("input_tokens"),
+        output_tokens: tokens("output_tokens"),
+        cache_read_tokens: tokens("cache_read_input_tokens"),
+        cache_write_tokens: tokens("cache_creation_input_tokens"),
+        cost_usd: result["total_cost_usd"].as_f64().unwrap_or(0.0),
+        models: result["modelUsage"].as_object().map(|m| m.keys().cloned().collect()).unwrap_or_default(),
+    })
+}
+
+fn handle_cleanup(branch: String, worktree: PathBuf, closed_session: Option<String>, verbose: bool, backend: IssueBackend) -> Result<()> {
+    let session_name = format!("fuzemill-{}", branch);
+
+    // The session-closed hook is global, so it fires for every session that closes.
+    if closed_session.is_some_and(|closed| closed != session_name) {
+        return Ok(());
     }
+
+    finish_session(&branch, &worktree, &session_name, verbose, backend)
 }
 
-fn create_new_issue_beads(args: &[String], cwd: &Path) -> Result<String> {
-    let output = Command::new("bd")
-        .arg("create")
-        .args(args)
-        .arg("--silent")
-        .current_dir(cwd)
+// Runs once a session has ended: opens the PR (unless the agent failed) and removes the worktree.
+fn finish_session(branch: &str, worktree_path: &Path, session_name: &str, verbose: bool, backend: IssueBackend) -> Result<()> {
+    if session_host(worktree_path, branch).is_alive(session_name) {
+        if verbose {
+            println!("Session '{}' is still running, skipping cleanup.", session_name);
+        }
+        return Ok(());
+    }
+
+    if !worktree_path.exists() {
+        remove_cleanup_hook(branch, worktree_path, verbose);
+        return Ok(());
+    }
+
+    // 'start' and the session-closed hook usually both get here at the same time
+    let Some(_lock) = FinishLock::acquire(worktree_path, branch)? else {
+        if verbose {
+            println!("Session for {} is already being finished by another process.", branch);
+        }
+        return Ok(());
+    };
+
+    let last_run = list_runs(worktree_path, branch)?.pop();
+    if let Some(run_dir) = &last_run
+        && let Err(e) = record_usage(worktree_path, branch, run_dir, worktree_path)
+    {
+        eprintln!("Warning: Failed to record token usage: {}", e);
+    }
+    let record = last_run.as_deref().and_then(|d| RunRecord::load(d).ok());
+    let agent_failed = record.as_ref().and_then(|r| r.exit_code).is_some_and(|code| code != 0);
+
+    // 'done' already ran the post-agent hook while the session was still open
+    if !record.as_ref().is_some_and(|r| r.done) {
+        let issue_id = record.as_ref().map(|r| r.issue_id.as_str()).unwrap_or(branch);
+        if let Err(e) = run_hook(worktree_path, "post-agent", issue_id, branch, worktree_path, verbose) {
+            eprintln!("Warning: {}", e);
+        }
+    }
+
+    if !agent_failed {
+        // The agent may have exited without running 'done', so the checks did not necessarily run
+        if !verify_checks(worktree_path, last_run.as_deref(), verbose)? {
+            remove_cleanup_hook(branch, worktree_path, verbose);
+            eprintln!(
+                "Warning: Checks failed for {}; not opening a PR. The worktree is kept at {}.",
+                branch,
+                worktree_path.display()
+            );
+            return Ok(());
+        }
+        if let Err(e) = ensure_pr(worktree_path, verbose, backend) {
+            eprintln!("Warning: Failed to open PR for {}: {}", branch, e);
+        }
+    }
+
+    cleanup_worktree(branch, worktree_path, verbose)
+}
+
+// Lock file in the issue's state dir, removed on drop. A lock older than a few minutes is
+// left over from a crashed process and is taken over.
+struct FinishLock(PathBuf);
+
+impl FinishLock {
+    fn acquire(git_root: &Path, issue_id: &str) -> Result<Option<Self>> {
+        let dir = issue_state_dir(git_root, issue_id)?;
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        let path = dir.join("finish.lock");
+
+        let stale = fs::metadata(&path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.elapsed().ok())
+            .is_some_and(|age| age.as_secs() > 600);
+        if stale {
+            let _ = fs::remove_file(&path);
+        }
+
+        match fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => Ok(Some(FinishLock(path))),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to create {}", path.display())),
+        }
+    }
+}
+
+impl Drop for FinishLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+// Removes the worktree of a finished session, unless it has uncommitted changes.
+fn cleanup_worktree(branch: &str, worktree_path: &Path, verbose: bool) -> Result<()> {
+    remove_cleanup_hook(branch, worktree_path, verbose);
+
+    if !worktree_path.exists() {
+        if verbose {
+            println!("Worktree at {} already removed.", worktree_path.display());
+        }
+        return Ok(());
+    }
+
+    let (main_repo_path, _) = get_git_common_dir(worktree_path)?;
+
+    if worktree_has_changes(worktree_path)? {
+        eprintln!("Warning: Worktree at {} has uncommitted changes, leaving it in place.", worktree_path.display());
+        return Ok(());
+    }
+
+    if verbose {
+        println!("Cleaning up worktree at {}...", worktree_path.display());
+    }
+    let status = Command::new("git")
+        .arg("worktree")
+        .arg("remove")
+        .arg(worktree_path)
+        .current_dir(&main_repo_path)
+        .status()
+        .context("Failed to execute git worktree remove")?;
+
+    // The hook and 'start' can race to remove the same worktree; only warn if it is still there.
+    if !status.success() && worktree_path.exists() {
+        eprintln!("Warning: Failed to remove worktree at {}", worktree_path.display());
+    }
+
+    Ok(())
+}
+
+fn worktree_has_changes(worktree_path: &Path) -> Result<bool> {
+    let output = worktree_git(worktree_path)?
+        .arg("status")
+        .arg("--porcelain")
         .output()
-        .context("Failed to execute 'bd create'")?;
+        .context("Failed to execute 'git status'")?;
 
     if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        eprintln!("{}", stderr);
-        bail!("Failed to create issue.");
+        bail!("git status failed in {}", worktree_path.display());
     }
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let issue_id = stdout.trim().to_string();
+    Ok(!output.stdout.is_empty())
+}
 
-    if issue_id.is_empty() {
-        bail!("'bd create' returned empty issue ID.");
-    }
+// git in an agent's worktree. Config the agent may have planted to make git run commands on
+// the host is overridden, so only the hooks fuzemill set up for the worktree run.
+fn worktree_git(worktree_path: &Path) -> Result<Command> {
+    let branch_hooks = get_current_branch(worktree_path)
+        .ok()
+        .and_then(|branch| issue_state_dir(worktree_path, &branch).ok())
+        .map(|dir| dir.join("hooks"))
+        .filter(|dir| dir.is_dir());
+    let hooks = match branch_hooks {
+        Some(dir) => dir,
+        None => state_dir(worktree_path)?.parent().context("Invalid git common dir")?.join("hooks"),
+    };
+    let mut cmd = Command::new("git");
+    cmd.arg("-c")
+        .arg("core.fsmonitor=")
+        .arg("-c")
+        .arg(format!("core.hooksPath={}", hooks.display()))
+        .current_dir(worktree_path);
+    Ok(cmd)
+}
 
-    println!("Created issue: {}", issue_id.green());
-    Ok(issue_id)
+// Resolves the 'gitdir:' line of a linked worktree's .git file. The entry itself may have
+// been pruned already, so only its parent is canonicalised.
+fn linked_gitdir(worktree_path: &Path) -> Option<PathBuf> {
+    let content = fs::read_to_string(worktree_path.join(".git")).ok()?;
+    let gitdir = PathBuf::from(content.lines().next()?.strip_prefix("gitdir:")?.trim());
+    let gitdir = if gitdir.is_relative() { worktree_path.join(gitdir) } else { gitdir };
+    Some(gitdir.parent()?.canonicalize().ok()?.join(gitdir.file_name()?))
 }
 
-fn create_new_issue_github(args: &[String], cwd: &Path) -> Result<String> {
-    if args.is_empty() {
-        bail!("Please provide a title for the issue.");
+// Terminal multiplexer (or plain terminal) that an interactive agent session runs in
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SessionHost {
+    Tmux,
+    Zellij,
+    Screen,
+    // The current terminal; the session lasts as long as the agent
+    Foreground,
+}
+
+// Input to send to the agent in a session
+enum Keys<'a> {
+    // Arrives as one bracketed paste, so newlines in it don't submit it
+    Paste(&'a str),
+    Enter,
+    Interrupt,
+}
+
+// Input for screen's 'stuff', which expands '^X' and backslash escapes, so the text escapes its own
+fn screen_input(keys: Keys) -> String {
+    match keys {
+        Keys::Paste(text) => format!("\\033[200~{}\\033[201~", text.replace('\\', "\\\\").replace('^', "\\^")),
+        Keys::Enter => "^M".to_string(),
+        Keys::Interrupt => "^C".to_string(),
     }
+}
 
-    let mut gh_args = vec!["issue", "create"];
+impl SessionHost {
+    const MULTIPLEXERS: [SessionHost; 3] = [SessionHost::Tmux, SessionHost::Zellij, SessionHost::Screen];
 
-    // Check if args use flags or positional
-    // If first arg starts with '-', treat as flags (compatible with gh)
-    // Otherwise, treat first arg as title
-    if !args[0].starts_with('-') {
-        gh_args.push("--title");
-        // We need to handle the title argument carefully
+    fn name(self) -> &'static str {
+        match self {
+            SessionHost::Tmux => "tmux",
+            SessionHost::Zellij => "zellij",
+            SessionHost::Screen => "screen",
+            SessionHost::Foreground => "foreground",
+        }
     }
 
-    let output = if !args[0].starts_with('-') {
-        // Positional: first arg is title, rest is body
-        let mut cmd = Command::new("gh");
-        cmd.arg("issue")
-            .arg("create")
-            .arg("--title")
-            .arg(&args[0]);
+    // Starts a detached session running the command. The foreground host runs it to completion instead.
+    fn create(self, session_name: &str, path: &Path, command: &str, verbose: bool) -> Result<()> {
+        if verbose {
+            println!("Creating {} session '{}'...", self.name(), session_name);
+        }
 
-        if args.len() > 1 {
-            let body = args[1..].join(" ");
-            cmd.arg("--body").arg(&body);
+        let status = match self {
+            // tmux new-session -d -s <name> -c <path> <command>
+            SessionHost::Tmux => Command::new("tmux")
+                .arg("new-session")
+                .arg("-d")
+                .arg("-s")
+                .arg(session_name)
+                .arg("-c")
+                .arg(path)
+                .arg(command)
+                .status(),
+            SessionHost::Zellij => {
+                // A layout with only the agent's pane, so the session ends with the agent
+                let layout = env::temp_dir().join(format!("{}.kdl", session_name));
+                fs::write(
+                    &layout,
+                    format!(
+                        "layout {{\n    pane command=\"sh\" cwd={} close_on_exit=true {{\n        args \"-c\" {}\n    }}\n}}\n",
+                        kdl_quote(&path.display().to_string()),
+                        kdl_quote(command)
+                    ),
+                )
+                .with_context(|| format!("Failed to write {}", layout.display()))?;
+                Command::new("zellij")
+                    .arg("--layout")
+                    .arg(&layout)
+                    .arg("attach")
+                    .arg("--create-background")
+                    .arg(session_name)
+                    .current_dir(path)
+                    .status()
+            }
+            SessionHost::Screen => Command::new("screen")
+                .arg("-dmS")
+                .arg(session_name)
+                .arg("sh")
+                .arg("-c")
+                .arg(command)
+                .current_dir(path)
+                .status(),
+            // The agent's exit code is recorded by the command itself. It must not find the
+            // multiplexer session fuzemill was started from, e.g. to kill it.
+            SessionHost::Foreground => {
+                Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .current_dir(path)
+                    .env_remove("TMUX")
+                    .env_remove("TMUX_PANE")
+                    .env_remove("STY")
+                    .env_remove("ZELLIJ")
+                    .env_remove("ZELLIJ_SESSION_NAME")
+                    .env_remove("ZELLIJ_PANE_ID")
+                    .status()
+                    .context("Failed to start the agent")?;
+                return Ok(());
+            }
+        }
+        .with_context(|| format!("Failed to create {} session", self.name()))?;
+
+        if !status.success() {
+            bail!("Failed to create {} session. Is {} installed?", self.name(), self.name());
+        }
+        Ok(())
+    }
+
+    // Returns when the session dies or the user detaches; callers check which one it was.
+    fn attach(self, session_name: &str, verbose: bool) -> Result<()> {
+        if verbose {
+            println!("Attaching to {} session...", self.name());
+        }
+
+        let _status = match self {
+            SessionHost::Tmux => Command::new("tmux")
+                .arg("attach")
+                .arg("-t")
+                .arg(format!("={}", session_name))
+                .status(),
+            SessionHost::Zellij => Command::new("zellij")
+                .arg("attach")
+                .arg(session_name)
+                .status(),
+            SessionHost::Screen => {
+                let Some(id) = screen_session_id(session_name) else {
+                    return Ok(());
+                };
+                Command::new("screen")
+                    .arg("-r")
+                    .arg(id)
+                    .status()
+            }
+            SessionHost::Foreground => return Ok(()),
+        }
+        .with_context(|| format!("Failed to attach to {} session", self.name()))?;
+
+        Ok(())
+    }
+
+    // Shown to the user to reattach to a session they detached from
+    fn attach_command(self, session_name: &str) -> String {
+        match self {
+            SessionHost::Tmux => format!("tmux attach -t {}", session_name),
+            SessionHost::Zellij => format!("zellij attach {}", session_name),
+            SessionHost::Screen => format!("screen -r {}", session_name),
+            SessionHost::Foreground => String::new(),
+        }
+    }
+
+    fn is_alive(self, session_name: &str) -> bool {
+        match self {
+            // '=' forces an exact match; otherwise 'fuzemill-1' would also match 'fuzemill-12'
+            SessionHost::Tmux => Command::new("tmux")
+                .arg("has-session")
+                .arg("-t")
+                .arg(format!("={}", session_name))
+                .output()
+                .map(|o| o.status.success())
+                .unwrap_or(false),
+            SessionHost::Zellij | SessionHost::Screen => self.list().iter().any(|name| name == session_name),
+            SessionHost::Foreground => false,
+        }
+    }
+
+    fn kill(self, session_name: &str) -> Result<()> {
+        let status = match self {
+            SessionHost::Tmux => Command::new("tmux")
+                .arg("kill-session")
+                .arg("-t")
+                .arg(format!("={}", session_name))
+                .status(),
+            SessionHost::Zellij => {
+                let status = Command::new("zellij")
+                    .arg("kill-session")
+                    .arg(session_name)
+                    .status();
+                // Killed sessions are kept around to be resurrected otherwise
+                let _ = Command::new("zellij")
+                    .arg("delete-session")
+                    .arg(session_name)
+                    .output();
+                status
+            }
+            SessionHost::Screen => {
+                let Some(id) = screen_session_id(session_name) else {
+                    return Ok(());
+                };
+                Command::new("screen")
+                    .arg("-S")
+                    .arg(id)
+                    .arg("-X")
+                    .arg("quit")
+                    .status()
+            }
+            SessionHost::Foreground => bail!("An agent in the foreground can only be ended from its terminal."),
+        }
+        .with_context(|| format!("Failed to kill {} session", self.name()))?;
+
+        if !status.success() {
+            bail!("Failed to kill {} session '{}'", self.name(), session_name);
+        }
+        Ok(())
+    }
+
+    // Names of the running sessions; empty if the host is not installed or has no server running
+    fn list(self) -> Vec<String> {
+        match self {
+            SessionHost::Tmux => {
+                let output = Command::new("tmux")
+                    .arg("list-sessions")
+                    .arg("-F")
+                    .arg("#{session_name}")
+                    .output();
+                match output {
+                    Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout).lines().map(String::from).collect(),
+                    _ => Vec::new(),
+                }
+            }
+            // 'name [Created 5m ago]', with '(EXITED - attach to resurrect)' for dead sessions
+            SessionHost::Zellij => {
+                let output = Command::new("zellij")
+                    .arg("list-sessions")
+                    .arg("--no-formatting")
+                    .output();
+                match output {
+                    Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+                        .lines()
+                        .filter(|l| !l.contains("EXITED"))
+                        .filter_map(|l| l.split_whitespace().next())
+                        .map(String::from)
+                        .collect(),
+                    _ => Vec::new(),
+                }
+            }
+            SessionHost::Screen => list_screen_sessions()
+                .into_iter()
+                .filter_map(|id| id.split_once('.').map(|(_, name)| name.to_string()))
+                .collect(),
+            SessionHost::Foreground => Vec::new(),
+        }
+    }
+
+    // The directory the session was started in. Zellij doesn't tell.
+    fn session_path(self, session_name: &str) -> Option<PathBuf> {
+        match self {
+            SessionHost::Tmux => {
+                let output = Command::new("tmux")
+                    .arg("display-message")
+                    .arg("-p")
+                    .arg("-t")
+                    .arg(format!("={}:", session_name))
+                    .arg("#{session_path}")
+                    .output()
+                    .ok()
+                    .filter(|o| o.status.success())?;
+                let path = String::from_utf8_lossy(&output.stdout).trim().to_string();
+                (!path.is_empty()).then(|| PathBuf::from(path))
+            }
+            // The screen process stays in the directory it was started in, even once it is deleted
+            SessionHost::Screen => {
+                let id = screen_session_id(session_name)?;
+                let pid = id.split_once('.')?.0;
+                let cwd = fs::read_link(format!("/proc/{}/cwd", pid)).ok()?;
+                let cwd = cwd.to_string_lossy();
+                Some(PathBuf::from(cwd.strip_suffix(" (deleted)").unwrap_or(&cwd)))
+            }
+            SessionHost::Zellij | SessionHost::Foreground => None,
+        }
+    }
+
+    fn send_keys(self, session_name: &str, keys: Keys) -> Result<()> {
+        let status = match self {
+            SessionHost::Tmux => {
+                let target = format!("={}:", session_name);
+                let mut cmd = Command::new("tmux");
+                match keys {
+                    // send-keys would type each newline as Enter
+                    Keys::Paste(text) => {
+                        let mut load = Command::new("tmux");
+                        load.arg("load-buffer")
+                            .arg("-b")
+                            .arg("fuzemill-say")
+                            .arg("-");
+                        let output = output_with_stdin(load, text).context("Failed to execute 'tmux load-buffer'")?;
+                        if !output.status.success() {
+                            bail!("tmux load-buffer failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+                        }
+                        cmd.arg("paste-buffer")
+                            .arg("-p")
+                            .arg("-d")
+                            .arg("-b")
+                            .arg("fuzemill-say")
+                            .arg("-t")
+                            .arg(&target)
+                    }
+                    Keys::Enter => cmd.arg("send-keys").arg("-t").arg(&target).arg("Enter"),
+                    Keys::Interrupt => cmd.arg("send-keys").arg("-t").arg(&target).arg("C-c"),
+                };
+                cmd.status()
+            }
+            SessionHost::Zellij => {
+                let mut cmd = Command::new("zellij");
+                cmd.arg("--session")
+                    .arg(session_name)
+                    .arg("action");
+                match keys {
+                    Keys::Paste(text) => cmd.arg("write-chars").arg(format!("\x1b[200~{}\x1b[201~", text)),
+                    Keys::Enter => cmd.arg("write").arg("13"),
+                    Keys::Interrupt => cmd.arg("write").arg("3"),
+                };
+                cmd.status()
+            }
+            SessionHost::Screen => {
+                let id = screen_session_id(session_name).with_context(|| format!("No screen session '{}'", session_name))?;
+                Command::new("screen")
+                    .arg("-S")
+                    .arg(id)
+                    .arg("-X")
+                    .arg("stuff")
+                    .arg(screen_input(keys))
+                    .status()
+            }
+            SessionHost::Foreground => bail!("Can't send input to an agent running in the foreground."),
+        }
+        .with_context(|| format!("Failed to send input to {} session", self.name()))?;
+
+        if !status.success() {
+            bail!("Failed to send input to {} session '{}'", self.name(), session_name);
+        }
+        Ok(())
+    }
+
+    // Appends everything the current session shows to the file
+    fn start_transcript(self, path: &Path) -> Result<()> {
+        match self {
+            SessionHost::Tmux => {
+                let Ok(pane) = env::var("TMUX_PANE") else {
+                    return Ok(());
+                };
+                let status = Command::new("tmux")
+                    .arg("pipe-pane")
+                    .arg("-o")
+                    .arg("-t")
+                    .arg(&pane)
+                    .arg(format!("cat >> {}", shell_quote(&path.display().to_string())))
+                    .status()
+                    .context("Failed to execute 'tmux pipe-pane'")?;
+                if !status.success() {
+                    bail!("tmux pipe-pane failed");
+                }
+            }
+            SessionHost::Screen => {
+                let Ok(id) = env::var("STY") else {
+                    return Ok(());
+                };
+                // screen flushes its log every 10 seconds by default
+                for command in [vec!["logfile", &path.display().to_string()], vec!["logfile", "flush", "1"], vec!["log", "on"]] {
+                    let status = Command::new("screen")
+                        .arg("-S")
+                        .arg(&id)
+                        .arg("-X")
+                        .args(&command)
+                        .status()
+                        .context("Failed to execute 'screen -X'")?;
+                    if !status.success() {
+                        bail!("screen -X {} failed", command.join(" "));
+                    }
+                }
+            }
+            // zellij can't stream a pane to a file; its screen is dumped when the agent exits
+            SessionHost::Zellij | SessionHost::Foreground => {}
+        }
+        Ok(())
+    }
+
+    // The last lines of output of the current session
+    fn capture_tail(self, lines: usize) -> Option<String> {
+        let text = match self {
+            SessionHost::Tmux => {
+                let pane = env::var("TMUX_PANE").ok()?;
+                let output = Command::new("tmux")
+                    .arg("capture-pane")
+                    .arg("-p")
+                    .arg("-J")
+                    .arg("-S")
+                    .arg(format!("-{}", lines))
+                    .arg("-t")
+                    .arg(&pane)
+                    .output()
+                    .ok()?;
+                if !output.status.success() {
+                    return None;
+                }
+                String::from_utf8_lossy(&output.stdout).to_string()
+            }
+            SessionHost::Zellij | SessionHost::Screen => {
+                let dump = env::temp_dir().join(format!("fuzemill-screen-{}.txt", std::process::id()));
+                let status = if self == SessionHost::Zellij {
+                    Command::new("zellij")
+                        .arg("action")
+                        .arg("dump-screen")
+                        .arg("--full")
+                        .arg(&dump)
+                        .status()
+                } else {
+                    Command::new("screen")
+                        .arg("-S")
+                        .arg(env::var("STY").ok()?)
+                        .arg("-X")
+                        .arg("hardcopy")
+                        .arg("-h")
+                        .arg(&dump)
+                        .status()
+                };
+                if !status.ok()?.success() {
+                    return None;
+                }
+                // screen writes the file after the command returns
+                let mut text = None;
+                for _ in 0..10 {
+                    text = fs::read_to_string(&dump).ok();
+                    if text.is_some() {
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(100));
+                }
+                let _ = fs::remove_file(&dump);
+                text?
+            }
+            SessionHost::Foreground => return None,
+        };
+        Some(format!("{}\n", last_lines(text.trim_end(), lines)))
+    }
+}
+
+// Host of the branch's latest session
+fn session_host(git_root: &Path, branch: &str) -> SessionHost {
+    list_runs(git_root, branch)
+        .ok()
+        .and_then(|runs| runs.last().and_then(|r| RunRecord::load(r).ok()))
+        .and_then(|r| r.runner.host())
+        .unwrap_or(SessionHost::Tmux)
+}
+
+// Ids ('<pid>.<name>') of the running screen sessions
+fn list_screen_sessions() -> Vec<String> {
+    // 'screen -ls' exits non-zero even when it lists sessions
+    let Ok(output) = Command::new("screen").arg("-ls").output() else {
+        return Vec::new();
+    };
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|l| l.starts_with('\t'))
+        .filter_map(|l| l.split_whitespace().next())
+        .map(String::from)
+        .collect()
+}
+
+// screen matches session names by prefix, so sessions are addressed by their full id
+fn screen_session_id(session_name: &str) -> Option<String> {
+    list_screen_sessions()
+        .into_iter()
+        .find(|id| id == session_name || id.split_once('.').is_some_and(|(_, name)| name == session_name))
+}
+
+fn kdl_quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// Hosts other than tmux have no hook for a closed session, so a detached process runs
+// the cleanup once the session is gone
+fn schedule_cleanup(worktree_path: &Path, backend: IssueBackend) -> Result<()> {
+    let branch = get_current_branch(worktree_path)?;
+    let cleanup_cmd = format!(
+        "{} cleanup {} --worktree {}",
+        fuzemill_command(backend),
+        shell_quote(&branch),
+        shell_quote(&worktree_path.display().to_string())
+    );
+    // Its own process group and ignoring SIGHUP, so it outlives the session
+    Command::new("sh")
+        .arg("-c")
+        .arg(format!("trap '' HUP; sleep 2; exec {}", cleanup_cmd))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .process_group(0)
+        .spawn()
+        .context("Failed to start the cleanup")?;
+    Ok(())
+}
+
+// tmux only runs session-closed hooks set globally (the session is gone by then), so each
+// issue gets its own slot in the global hook array. The slot is derived from the issue and
+// worktree so that 'cleanup' can find and unset it later without any stored state.
+fn cleanup_hook_name(branch: &str, worktree_path: &Path) -> String {
+    let key = format!("{}\0{}", branch, worktree_path.display());
+    let hash = key.bytes().fold(5381u32, |h, b| h.wrapping_mul(33) ^ b as u32);
+    format!("session-closed[{}]", 1000 + hash % 1_000_000)
+}
+
+fn install_cleanup_hook(branch: &str, worktree_path: &Path, session_name: &str, verbose: bool, backend: IssueBackend) -> Result<()> {
+    let cleanup_cmd = format!(
+        "{} cleanup {} --worktree {} --closed-session '#{{hook_session_name}}'",
+        fuzemill_command(backend),
+        shell_quote(branch),
+        shell_quote(&worktree_path.display().to_string())
+    );
+    let hook_name = cleanup_hook_name(branch, worktree_path);
+
+    if verbose {
+        println!("Installing tmux hook {} for {}...", hook_name, session_name);
+    }
+
+    let status = Command::new("tmux")
+        .arg("set-hook")
+        .arg("-g")
+        .arg(&hook_name)
+        .arg(format!("run-shell -b {}", tmux_quote(&cleanup_cmd)))
+        .status()
+        .context("Failed to execute 'tmux set-hook'")?;
+
+    if !status.success() {
+        bail!("tmux set-hook failed");
+    }
+    Ok(())
+}
+
+fn remove_cleanup_hook(branch: &str, worktree_path: &Path, verbose: bool) {
+    let hook_name = cleanup_hook_name(branch, worktree_path);
+    if verbose {
+        println!("Removing tmux hook {}...", hook_name);
+    }
+    // Best effort: there is nothing to remove if the tmux server has already exited.
+    let _ = Command::new("tmux")
+        .arg("set-hook")
+        .arg("-gu")
+        .arg(&hook_name)
+        .output();
+}
+
+// Shell command that re-invokes fuzemill with the same issue backend, for use in hooks and prompts
+fn fuzemill_command(backend: IssueBackend) -> String {
+    let current_exe = env::current_exe().unwrap_or_else(|_| PathBuf::from("fuzemill"));
+    match backend {
+        IssueBackend::Beads => format!("{} --use-bd", shell_quote(&current_exe.display().to_string())),
+        IssueBackend::GitHub => shell_quote(&current_exe.display().to_string()),
+    }
+}
+
+// Wraps a string in single quotes for sh, unless it is made of characters that need none
+fn shell_quote(s: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "/._-+=:@%,".contains(c);
+    if !s.is_empty() && s.chars().all(is_safe) {
+        return s.to_string();
+    }
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+// Wraps a string in double quotes for the tmux command parser
+fn tmux_quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\"").replace('$', "\\$"))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+struct DoctorCheck {
+    name: &'static str,
+    status: CheckStatus,
+    detail: String,
+    hint: Option<String>,
+}
+
+impl DoctorCheck {
+    fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+        DoctorCheck { name, status: CheckStatus::Pass, detail: detail.into(), hint: None }
+    }
+
+    fn warn(name: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        DoctorCheck { name, status: CheckStatus::Warn, detail: detail.into(), hint: Some(hint.into()) }
+    }
+
+    fn fail(name: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        DoctorCheck { name, status: CheckStatus::Fail, detail: detail.into(), hint: Some(hint.into()) }
+    }
+}
+
+fn handle_doctor(agent: String, verbose: bool, backend: IssueBackend) -> Result<()> {
+    let current_dir = env::current_dir().context("Failed to get current directory")?;
+    let git_root = find_git_root(&current_dir);
+
+    if verbose {
+        println!("Running checks for agent '{}'...", agent);
+    }
+
+    let checks = run_doctor_checks(git_root.as_deref(), &agent, backend, false);
+    print_doctor_checks(&checks);
+
+    let failures = checks.iter().filter(|c| c.status == CheckStatus::Fail).count();
+    if failures > 0 {
+        bail!("{} check(s) failed", failures);
+    }
+    println!("{}", "All required checks passed.".green());
+    Ok(())
+}
+
+// Runs before 'start' touches anything, so a missing tool fails with a clear message
+// instead of a half-created worktree or a tmux pane that dies instantly.
+fn preflight(git_root: &Path, agent: &str, verbose: bool, backend: IssueBackend) -> Result<()> {
+    let checks = run_doctor_checks(Some(git_root), agent, backend, true);
+    let failed: Vec<&DoctorCheck> = checks.iter().filter(|c| c.status == CheckStatus::Fail).collect();
+
+    if verbose {
+        print_doctor_checks(&checks);
+    } else if !failed.is_empty() {
+        for check in &failed {
+            print_doctor_check(check);
+        }
+    }
+
+    if !failed.is_empty() {
+        bail!("Preflight checks failed. Run 'fuzemill doctor' for a full report.");
+    }
+    Ok(())
+}
+
+// The quick variant skips checks that need the network or only matter for optional tools.
+fn run_doctor_checks(git_root: Option<&Path>, agent: &str, backend: IssueBackend, quick: bool) -> Vec<DoctorCheck> {
+    let mut checks = vec![check_git(git_root)];
+    // Headless and foreground runs don't need a multiplexer, e.g. on a CI box
+    let runner = git_root.and_then(|r| load_config(r).ok()).and_then(|c| c.runner.resolve().ok()).unwrap_or_default();
+    if let Some(host) = runner.host()
+        && host != SessionHost::Foreground
+    {
+        checks.push(check_session_host(host));
+    }
+
+    if quick {
+        checks.push(match command_version("gh", &["--version"]) {
+            Some(version) => DoctorCheck::pass("gh", version),
+            None => DoctorCheck::fail("gh", "not found on PATH", "Install the GitHub CLI: https://cli.github.com"),
+        });
+    } else {
+        checks.push(check_gh_auth());
+    }
+
+    if backend == IssueBackend::Beads || !quick {
+        checks.push(check_beads(git_root, backend));
+    }
+
+    checks.push(check_agent(agent));
+
+    if let Some(check) = git_root.and_then(|r| load_config(r).ok()).and_then(|c| check_sandbox(&c.sandbox)) {
+        checks.push(check);
+    }
+
+    if !quick {
+        checks.push(check_direnv(git_root));
+    }
+
+    checks.push(check_worktree_parent(git_root));
+    checks
+}
+
+fn print_doctor_checks(checks: &[DoctorCheck]) {
+    for check in checks {
+        print_doctor_check(check);
+    }
+}
+
+fn print_doctor_check(check: &DoctorCheck) {
+    let mark = match check.status {
+        CheckStatus::Pass => "ok".green(),
+        CheckStatus::Warn => "warn".yellow(),
+        CheckStatus::Fail => "FAIL".red().bold(),
+    };
+    println!("[{:>4}] {:<16} {}", mark, check.name, check.detail);
+    if let (CheckStatus::Warn | CheckStatus::Fail, Some(hint)) = (check.status, &check.hint) {
+        println!("       {:<16} fix: {}", "", hint);
+    }
+}
+
+// Returns the first line of a successful '<program> <args>' run
+fn command_version(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let text = if stdout.trim().is_empty() { stderr } else { stdout };
+    Some(text.lines().next().unwrap_or("").trim().to_string())
+}
+
+fn check_git(git_root: Option<&Path>) -> DoctorCheck {
+    let Some(version) = command_version("git", &["--version"]) else {
+        return DoctorCheck::fail("git", "not found on PATH", "Install git 2.31 or newer");
+    };
+
+    // 'rev-parse --path-format=absolute' (used to locate the main repo) needs git 2.31
+    let number = version.trim_start_matches("git version ").split_whitespace().next().unwrap_or("");
+    let mut parts = number.split('.').filter_map(|p| p.parse::<u32>().ok());
+    let (major, minor) = (parts.next().unwrap_or(0), parts.next().unwrap_or(0));
+    if (major, minor) < (2, 31) {
+        return DoctorCheck::fail("git", version, "Upgrade git to 2.31 or newer");
+    }
+
+    let Some(git_root) = git_root else {
+        return DoctorCheck::fail("git", format!("{} (not in a git repository)", version), "Run fuzemill from inside a git repository");
+    };
+
+    let worktrees_ok = Command::new("git")
+        .arg("worktree")
+        .arg("list")
+        .current_dir(git_root)
+        .output()
+        .map(|o| o.status.success())
+        .unwrap_or(false);
+
+    if worktrees_ok {
+        DoctorCheck::pass("git", format!("{} (worktrees supported)", version))
+    } else {
+        DoctorCheck::fail("git", format!("{} ('git worktree list' failed)", version), "Check that the repository is not bare or corrupted")
+    }
+}
+
+fn check_session_host(host: SessionHost) -> DoctorCheck {
+    let (args, fix): (&[&str], &str) = match host {
+        SessionHost::Tmux => (&["-V"], "Install tmux (e.g. 'brew install tmux' or 'apt install tmux')"),
+        SessionHost::Zellij => (&["--version"], "Install zellij: https://zellij.dev"),
+        SessionHost::Screen => (&["-v"], "Install GNU screen (e.g. 'brew install screen' or 'apt install screen')"),
+        SessionHost::Foreground => return DoctorCheck::pass("terminal", "the agent runs in the foreground".to_string()),
+    };
+    // 'screen -v' exits non-zero, so only the output counts
+    let version = Command::new(host.name()).args(args).output().ok().map(|o| {
+        let text = if o.stdout.is_empty() { o.stderr } else { o.stdout };
+        String::from_utf8_lossy(&text).lines().next().unwrap_or("").trim().to_string()
+    });
+    match version {
+        Some(version) => DoctorCheck::pass(host.name(), version),
+        None => DoctorCheck::fail(host.name(), "not found on PATH", fix),
+    }
+}
+
+// None when no sandbox is configured
+fn check_sandbox(config: &SandboxConfig) -> Option<DoctorCheck> {
+    match config.mode {
+        SandboxMode::None => None,
+        SandboxMode::Bwrap => {
+            let Some(version) = command_version("bwrap", &["--version"]) else {
+                return Some(DoctorCheck::fail("sandbox", "bwrap not found on PATH", "Install bubblewrap (e.g. 'apt install bubblewrap')"));
+            };
+            // Distributions may disable the unprivileged user namespaces bwrap needs
+            let works = Command::new("bwrap")
+                .arg("--unshare-all")
+                .arg("--ro-bind")
+                .arg("/")
+                .arg("/")
+                .arg("true")
+                .output()
+                .is_ok_and(|o| o.status.success());
+            Some(if works {
+                DoctorCheck::pass("sandbox", version)
+            } else {
+                DoctorCheck::fail(
+                    "sandbox",
+                    format!("{} can't create a sandbox", version),
+                    "Enable unprivileged user namespaces (e.g. 'sysctl kernel.apparmor_restrict_unprivileged_userns=0')",
+                )
+            })
+        }
+        SandboxMode::Podman => {
+            let Some(version) = command_version("podman", &["--version"]) else {
+                return Some(DoctorCheck::fail("sandbox", "podman not found on PATH", "Install podman: https://podman.io"));
+            };
+            let Some(image) = &config.image else {
+                return Some(DoctorCheck::fail("sandbox", "no image configured", "Set 'image' under [sandbox] in .fuzemill.toml"));
+            };
+            let pulled = Command::new("podman")
+                .arg("image")
+                .arg("exists")
+                .arg(image)
+                .status()
+                .is_ok_and(|s| s.success());
+            Some(if pulled {
+                DoctorCheck::pass("sandbox", format!("{}, image {}", version, image))
+            } else {
+                DoctorCheck::warn("sandbox", format!("image {} is not pulled yet", image), format!("Run 'podman pull {}'", image))
+            })
+        }
+    }
+}
+
+fn check_gh_auth() -> DoctorCheck {
+    if command_version("gh", &["--version"]).is_none() {
+        return DoctorCheck::fail("gh", "not found on PATH", "Install the GitHub CLI: https://cli.github.com");
+    }
+
+    let output = Command::new("gh")
+        .arg("auth")
+        .arg("status")
+        .output();
+
+    match output {
+        Ok(output) if output.status.success() => DoctorCheck::pass("gh", "authenticated"),
+        Ok(output) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let detail = stderr.lines().find(|l| !l.trim().is_empty()).unwrap_or("not authenticated").trim().to_string();
+            DoctorCheck::fail("gh", detail, "Run 'gh auth login'")
+        }
+        Err(e) => DoctorCheck::fail("gh", e.to_string(), "Run 'gh auth login'"),
+    }
+}
+
+fn check_beads(git_root: Option<&Path>, backend: IssueBackend) -> DoctorCheck {
+    let required = backend == IssueBackend::Beads;
+    let missing = |detail: &str, hint: &str| {
+        if required {
+            DoctorCheck::fail("bd", detail, hint)
+        } else {
+            DoctorCheck::warn("bd", format!("{} (optional, only needed with --use-bd)", detail), hint)
+        }
+    };
+
+    let Some(version) = command_version("bd", &["--version"]) else {
+        return missing("not found on PATH", "Install the Beads CLI");
+    };
+
+    let Some(git_root) = git_root else {
+        return DoctorCheck::pass("bd", version);
+    };
+
+    let output = Command::new("bd")
+        .arg("list")
+        .current_dir(git_root)
+        .output();
+
+    match output {
+        Ok(output) if output.status.success() => DoctorCheck::pass("bd", format!("{} (database found)", version)),
+        Ok(output) if String::from_utf8_lossy(&output.stderr).contains("no beads database found") => {
+            missing("no beads database in this repository", "Run 'bd init'")
+        }
+        _ => missing("'bd list' failed", "Run 'bd list' to see the error"),
+    }
+}
+
+fn check_agent(agent: &str) -> DoctorCheck {
+    let hint = match agent {
+        "claude" => "Install Claude Code: https://claude.ai/code",
+        "gemini" => "Install the Gemini CLI: https://github.com/google-gemini/gemini-cli",
+        _ => return DoctorCheck::fail("agent", format!("unknown agent '{}'", agent), "Use '--agent claude' or '--agent gemini'"),
+    };
+
+    match command_version(agent, &["--version"]) {
+        Some(version) => DoctorCheck::pass("agent", format!("{} {}", agent, version)),
+        None => DoctorCheck::fail("agent", format!("'{}' not found on PATH", agent), hint),
+    }
+}
+
+fn check_direnv(git_root: Option<&Path>) -> DoctorCheck {
+    let needs_direnv = git_root.is_some_and(|root| root.join(".envrc").exists());
+
+    match command_version("direnv", &["version"]) {
+        Some(version) => DoctorCheck::pass("direnv", version),
+        None if needs_direnv => DoctorCheck::warn("direnv", "not found, but this repository has an .envrc", "Install direnv so worktrees get the project environment"),
+        None => DoctorCheck::pass("direnv", "not installed (not needed, no .envrc)"),
+    }
+}
+
+fn check_worktree_parent(git_root: Option<&Path>) -> DoctorCheck {
+    let Some(git_root) = git_root else {
+        return DoctorCheck::fail("worktree dir", "not in a git repository", "Run fuzemill from inside a git repository");
+    };
+    let Some(parent) = git_root.parent() else {
+        return DoctorCheck::fail("worktree dir", "repository has no parent directory", "Move the repository out of the filesystem root");
+    };
+
+    // Worktrees are created as siblings of the repository
+    let probe = parent.join(format!(".fuzemill-doctor-{}", std::process::id()));
+    match fs::write(&probe, b"") {
+        Ok(()) => {
+            let _ = fs::remove_file(&probe);
+            DoctorCheck::pass("worktree dir", format!("{} is writable", parent.display()))
+        }
+        Err(e) => DoctorCheck::fail("worktree dir", format!("cannot write to {}: {}", parent.display(), e), "Make the parent directory of the repository writable"),
+    }
+}
+
+fn update_issue_status(cwd: &Path, issue_id: &str, status: &str, verbose: bool, backend: IssueBackend) -> Result<()> {
+    match backend {
+        IssueBackend::Beads => update_issue_status_beads(cwd, issue_id, status, verbose),
+        IssueBackend::GitHub => update_issue_status_github(cwd, issue_id, status, verbose),
+    }
+}
+
+fn update_issue_status_beads(cwd: &Path, issue_id: &str, status: &str, verbose: bool) -> Result<()> {
+    if verbose {
+        println!("Updating bead {} status to '{}'...", issue_id, status);
+    }
+
+    let output = Command::new("bd")
+        .arg("update")
+        .arg(issue_id)
+        .arg("--status")
+        .arg(status)
+        .current_dir(cwd)
+        .output()
+        .context("Failed to execute 'bd update'")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("bd update failed: {}", stderr.trim());
+    }
+    Ok(())
+}
+
+fn update_issue_status_github(cwd: &Path, issue_id: &str, status: &str, verbose: bool) -> Result<()> {
+    // GitHub Issues don't have custom statuses like beads.
+    // We use labels to track status (e.g., "status:hooked", "status:in_progress")
+    let label = format!("status:{}", status);
+
+    if verbose {
+        println!("Updating GitHub issue #{} status to '{}' via label...", issue_id, label);
+    }
+
+    // Add the status label (best effort - won't fail if label doesn't exist)
+    let output = Command::new("gh")
+        .arg("issue")
+        .arg("edit")
+        .arg(issue_id)
+        .arg("--add-label")
+        .arg(&label)
+        .current_dir(cwd)
+        .output()
+        .context("Failed to execute 'gh issue edit'")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        // Don't fail if label doesn't exist - status labels are optional
+        if verbose {
+            eprintln!("Warning: Could not add label '{}': {}", label, stderr.trim());
+        }
+    }
+    Ok(())
+}
+
+// Builds the shell command that starts the agent. The interactive form runs in a terminal;
+// the headless form works on the prompt without asking anything and exits when done.
+fn agent_command(agent: &str, model: Option<&str>, run_dir: &Path, headless: bool, permissions: PermissionMode) -> Result<String> {
+    let mut cmd = match (agent, headless) {
+        ("gemini", false) => String::from("gemini --prompt-interactive"),
+        ("gemini", true) => String::from("gemini --prompt"),
+        ("claude", false) => String::from("claude"),
+        // stream-json streams progress to the log and ends with the usage of the run
+        ("claude", true) => String::from("claude --print --output-format stream-json --verbose"),
+        _ => bail!("Unknown agent '{}'. Use 'claude' or 'gemini'.", agent),
+    };
+    // Otherwise the settings file written by write_agent_permissions decides
+    match (agent, permissions) {
+        ("gemini", PermissionMode::Yolo) => cmd.push_str(" --yolo"),
+        ("gemini", _) => cmd.push_str(" --approval-mode default"),
+        (_, PermissionMode::Yolo) => cmd.push_str(" --dangerously-skip-permissions"),
+        _ => {}
+    }
+    if let Some(m) = model {
+        cmd.push_str(&format!(" --model {}", m));
+    }
+    // The shell reads the prompt from the run directory. Passing it inline would hit
+    // tmux's command length limit for long prompts (e.g. review feedback).
+    cmd.push_str(&format!(" \"$(cat {})\"", shell_quote(&run_dir.join("prompt.md").display().to_string())));
+    Ok(cmd)
+}
+
+// Writes the agent's settings file into the worktree for the 'ask' and 'allowlist' policies.
+// Both allow the commands of fuzemill's workflow; 'allowlist' adds the configured commands
+// and paths. Existing settings in the file are kept.
+fn write_agent_permissions(config: &PermissionsConfig, session: &AgentSession, backend: IssueBackend) -> Result<()> {
+    if config.mode == PermissionMode::Yolo {
+        return Ok(());
+    }
+
+    let issue_view_cmd = match backend {
+        IssueBackend::Beads => "bd show",
+        IssueBackend::GitHub => "gh issue view",
+    };
+    let mut commands = vec![
+        issue_view_cmd.to_string(),
+        "git status".to_string(),
+        "git diff".to_string(),
+        "git log".to_string(),
+        "git show".to_string(),
+        "git add".to_string(),
+        "git commit".to_string(),
+        format!("{} done", fuzemill_command(backend)),
+    ];
+    let mut paths = Vec::new();
+    if config.mode == PermissionMode::Allowlist {
+        commands.extend(config.commands.iter().cloned());
+        paths.extend(config.paths.iter().cloned());
+    }
+
+    let (rel_path, section, key, allowed) = match session.agent.as_str() {
+        "claude" => {
+            let rules = commands
+                .iter()
+                .map(|c| format!("Bash({}:*)", c))
+                // Edit rules cover all of Claude's file-editing tools
+                .chain(paths.iter().map(|p| format!("Edit({})", p)))
+                .collect::<Vec<_>>();
+            (".claude/settings.local.json", "permissions", "allow", rules)
+        }
+        // Gemini has no rules for paths, so it asks before every edit
+        "gemini" => {
+            let rules = commands.iter().map(|c| format!("run_shell_command({})", c)).collect::<Vec<_>>();
+            (".gemini/settings.json", "tools", "allowed", rules)
+        }
+        agent => bail!("Unknown agent '{}'. Use 'claude' or 'gemini'.", agent),
+    };
+
+    let path = session.worktree.join(rel_path);
+    let mut settings = match fs::read_to_string(&path) {
+        Ok(content) => serde_json::from_str(&content).with_context(|| format!("Failed to parse {}", path.display()))?,
+        Err(_) => serde_json::json!({}),
+    };
+    let Some(settings_map) = settings.as_object_mut() else {
+        bail!("{} is not a JSON object", path.display());
+    };
+    let section = settings_map.entry(section).or_insert_with(|| serde_json::json!({}));
+    let Some(section) = section.as_object_mut() else {
+        bail!("Unexpected settings in {}", path.display());
+    };
+    let list = section.entry(key).or_insert_with(|| serde_json::json!([]));
+    if let Some(list) = list.as_array_mut() {
+        for rule in allowed {
+            if !list.iter().any(|r| r.as_str() == Some(&rule)) {
+                list.push(serde_json::Value::String(rule));
+            }
+        }
+    }
+    if session.agent == "claude" {
+        // Overrides a bypassPermissions default from the user's own settings
+        section.insert("defaultMode".to_string(), serde_json::json!("default"));
+    }
+
+    keep_out_of_commits(&session.worktree, rel_path)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    fs::write(&path, serde_json::to_string_pretty(&settings)? + "\n").with_context(|| format!("Failed to write {}", path.display()))
+}
+
+// The prompt is gone from the agent's context after a compaction, but instruction files are
+// read again. This renders the instructions template into a marked section of the agent's
+// instruction file in the worktree, replacing the section of an earlier run. The section must
+// never be committed, so a file the repository tracks is passed over for one it doesn't.
+fn write_agent_instructions(config: &Config, session: &AgentSession, backend: IssueBackend) -> Result<()> {
+    let candidates = match (&config.instructions.file, session.agent.as_str()) {
+        (Some(file), _) => vec![file.as_str()],
+        (None, "claude") => vec!["CLAUDE.md", "CLAUDE.local.md"],
+        (None, "gemini") => vec!["GEMINI.md", "AGENTS.md"],
+        (None, agent) => bail!("Unknown agent '{}'. Use 'claude' or 'gemini'.", agent),
+    };
+    let Some(rel_path) = candidates.iter().copied().find(|p| !is_tracked(&session.worktree, p)) else {
+        bail!("{} is tracked by the repository. Set 'file' under [instructions] in .fuzemill.toml to an untracked file.", candidates.join(" and "));
+    };
+
+    let issue_view_cmd = match backend {
+        IssueBackend::Beads => format!("bd show {}", session.issue_id),
+        IssueBackend::GitHub => format!("gh issue view {}", session.issue_id),
+    };
+    let title = fetch_issue_title(&session.worktree, &session.issue_id, backend).unwrap_or_else(|| "unknown".to_string());
+    let done_cmd = format!("{} done", fuzemill_command(backend));
+    let pr = if config.pr.create {
+        format!("Do not push or open a PR yourself; '{}' pushes the branch and opens the PR.", done_cmd)
+    } else {
+        "Push the branch and open a PR yourself.".to_string()
+    };
+    let checks = if config.checks.is_empty() {
+        String::new()
+    } else {
+        let commands: Vec<String> = config.checks.iter().map(|c| format!("`{}`", c.run)).collect();
+        format!("- '{}' runs these checks: {}. If one fails, fix it, commit, and run it again.\n", done_cmd, commands.join(", "))
+    };
+
+    let section = render_template(
+        &config.instructions.template,
+        &[
+            ("issue", &session.issue_id),
+            ("title", &title),
+            ("issue_view", &issue_view_cmd),
+            ("branch", &session.branch),
+            ("agent", &session.agent),
+            ("co_author", agent_co_author(&session.agent)?),
+            ("done", &done_cmd),
+            ("pr", &pr),
+            ("checks", &checks),
+        ],
+    );
+    let section = format!("{}\n{}\n{}\n", INSTRUCTIONS_BEGIN, section.trim(), INSTRUCTIONS_END);
+
+    let path = session.worktree.join(rel_path);
+    let existing = fs::read_to_string(&path).unwrap_or_default();
+    let content = match (existing.find(INSTRUCTIONS_BEGIN), existing.find(INSTRUCTIONS_END)) {
+        (Some(begin), Some(end)) if begin < end => {
+            let end = end + INSTRUCTIONS_END.len();
+            let rest = existing[end..].strip_prefix('\n').unwrap_or(&existing[end..]);
+            format!("{}{}{}", &existing[..begin], section, rest)
+        }
+        _ if existing.trim().is_empty() => section,
+        _ => format!("{}\n\n{}", existing.trim_end(), section),
+    };
+
+    keep_out_of_commits(&session.worktree, rel_path)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    fs::write(&path, content).with_context(|| format!("Failed to write {}", path.display()))?;
+
+    // Gemini only reads GEMINI.md unless told otherwise
+    if session.agent == "gemini" && rel_path != "GEMINI.md" {
+        add_gemini_context_file(session, rel_path)?;
+    }
+    Ok(())
+}
+
+// Adds the file to the ones Gemini reads its instructions from, next to GEMINI.md
+fn add_gemini_context_file(session: &AgentSession, file: &str) -> Result<()> {
+    let rel_path = ".gemini/settings.json";
+    let path = session.worktree.join(rel_path);
+    let mut settings = match fs::read_to_string(&path) {
+        Ok(content) => serde_json::from_str(&content).with_context(|| format!("Failed to parse {}", path.display()))?,
+        Err(_) => serde_json::json!({}),
+    };
+    let Some(settings_map) = settings.as_object_mut() else {
+        bail!("{} is not a JSON object", path.display());
+    };
+    let context = settings_map.entry("context").or_insert_with(|| serde_json::json!({}));
+    let Some(context) = context.as_object_mut() else {
+        bail!("Unexpected settings in {}", path.display());
+    };
+    let names = context.entry("fileName").or_insert_with(|| serde_json::json!(["GEMINI.md"]));
+    if let Some(name) = names.as_str() {
+        *names = serde_json::json!([name]);
+    }
+    if let Some(names) = names.as_array_mut()
+        && !names.iter().any(|n| n.as_str() == Some(file))
+    {
+        names.push(serde_json::Value::String(file.to_string()));
+    }
+
+    keep_out_of_commits(&session.worktree, rel_path)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    fs::write(&path, serde_json::to_string_pretty(&settings)? + "\n").with_context(|| format!("Failed to write {}", path.display()))
+}
+
+const INSTRUCTIONS_BEGIN: &str = "<!-- fuzemill:begin (generated for this worktree, not committed) -->";
+const INSTRUCTIONS_END: &str = "<!-- fuzemill:end -->";
+
+// Git hooks that the worktree's hooks directory passes on to the repository's own hooks
+const GIT_HOOKS: &[&str] = &[
+    "applypatch-msg",
+    "pre-applypatch",
+    "post-applypatch",
+    "pre-commit",
+    "pre-merge-commit",
+    "prepare-commit-msg",
+    "commit-msg",
+    "post-commit",
+    "pre-rebase",
+    "post-checkout",
+    "post-merge",
+    "pre-push",
+    "post-rewrite",
+    "reference-transaction",
+    "push-to-checkout",
+    "pre-auto-gc",
+    "post-index-change",
+];
+
+// Agents don't reliably add the trailers the prompt asks for, so a commit-msg hook adds them.
+// It is set up for the worktree alone (core.hooksPath in its worktree config) and then runs
+// the repository's own hooks; the other hooks only pass on to those.
+fn install_commit_hooks(git_root: &Path, session: &AgentSession) -> Result<()> {
+    let hooks_dir = issue_state_dir(git_root, &session.branch)?.join("hooks");
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .args(args)
+            .current_dir(&session.worktree)
+            .output()
+            .with_context(|| format!("Failed to execute 'git {}'", args.join(" ")))
+    };
+
+    // The repository's own hooks, without the ones set up by an earlier run
+    set_worktree_config(&session.worktree, &[("core.hooksPath", None)])?;
+    let output = git(&["rev-parse", "--path-format=absolute", "--git-path", "hooks"])?;
+    if !output.status.success() {
+        bail!("git rev-parse --git-path hooks failed");
+    }
+    let repo_hooks = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+
+    let trailers = [
+        format!("Co-authored-by: {}", agent_co_author(&session.agent)?),
+        format!("Fuzemill-Issue: {}", session.issue_id),
+        format!("Fuzemill-Model: {}", session.model.as_deref().unwrap_or("default")),
+    ];
+    let trailer_args: Vec<String> = trailers.iter().map(|t| format!("--trailer {}", shell_quote(t))).collect();
+
+    fs::create_dir_all(&hooks_dir).with_context(|| format!("Failed to create {}", hooks_dir.display()))?;
+    for name in GIT_HOOKS {
+        let repo_hook = shell_quote(&repo_hooks.join(name).display().to_string());
+        let mut script = String::from("#!/bin/sh\n# Set up by fuzemill for this worktree\n");
+        if *name == "commit-msg" {
+            script.push_str(&format!("git interpret-trailers --in-place --if-exists addIfDifferent {} \"$1\" || exit 1\n", trailer_args.join(" ")));
+        } else if !repo_hooks.join(name).exists() {
+            continue;
+        }
+        script.push_str(&format!("[ -x {} ] && exec {} \"$@\"\nexit 0\n", repo_hook, repo_hook));
+
+        let path = hooks_dir.join(name);
+        fs::write(&path, script).with_context(|| format!("Failed to write {}", path.display()))?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).with_context(|| format!("Failed to make {} executable", path.display()))?;
+    }
+
+    set_worktree_config(&session.worktree, &[("core.hooksPath", Some(&hooks_dir.display().to_string()))])
+}
+
+// Sets (or with None, unsets) config for this worktree alone
+fn set_worktree_config(worktree: &Path, settings: &[(&str, Option<&str>)]) -> Result<()> {
+    // Without worktreeConfig, --worktree would set it for every worktree of the repository
+    let mut commands = vec![vec!["config", "extensions.worktreeConfig", "true"]];
+    for (key, value) in settings {
+        commands.push(match value {
+            Some(value) => vec!["config", "--worktree", key, value],
+            None => vec!["config", "--worktree", "--unset-all", key],
+        });
+    }
+
+    for args in commands {
+        let output = Command::new("git")
+            .args(&args)
+            .current_dir(worktree)
+            .output()
+            .with_context(|| format!("Failed to execute 'git {}'", args.join(" ")))?;
+        // Unsetting a key that isn't set exits with 5
+        let was_unset = args[2] == "--unset-all" && output.status.code() == Some(5);
+        if !output.status.success() && !was_unset {
+            bail!("git {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim());
+        }
+    }
+    Ok(())
+}
+
+// Applies the agent's configured git identity and signing to its worktree. Settings of an
+// earlier run are replaced, so a changed configuration takes effect on the next run.
+fn apply_agent_identity(identity: &IdentityConfig, worktree: &Path) -> Result<()> {
+    let signing_key = match (identity.signing, &identity.signing_key) {
+        (Signing::Key, Some(key)) => {
+            let key = expand_home(key);
+            if !key.exists() {
+                bail!("The signing key {} does not exist.", key.display());
+            }
+            Some(key.display().to_string())
+        }
+        (Signing::Key, None) => bail!("signing = \"key\" needs a signing_key in the agent's [identity] section."),
+        _ => None,
+    };
+    let gpgsign = match identity.signing {
+        Signing::Inherit => None,
+        Signing::Key => Some("true"),
+        Signing::None | Signing::Merge => Some("false"),
+    };
+    let format = signing_key.as_ref().map(|_| "ssh");
+
+    set_worktree_config(
+        worktree,
+        &[
+            ("user.name", identity.name.as_deref()),
+            ("user.email", identity.email.as_deref()),
+            ("commit.gpgsign", gpgsign),
+            ("gpg.format", format),
+            ("user.signingkey", signing_key.as_deref()),
+        ],
+    )
+}
+
+fn is_tracked(worktree: &Path, rel_path: &str) -> bool {
+    Command::new("git")
+        .arg("ls-files")
+        .arg("--error-unmatch")
+        .arg(rel_path)
+        .current_dir(worktree)
+        .output()
+        .is_ok_and(|o| o.status.success())
+}
+
+// Keeps a file fuzemill writes into the worktree out of the agent's commits. info/exclude
+// doesn't apply to tracked files, and hiding changes to those would hide the repository's own.
+fn keep_out_of_commits(worktree: &Path, rel_path: &str) -> Result<()> {
+    if is_tracked(worktree, rel_path) {
+        bail!("{} is tracked by the repository, so fuzemill won't write to it", rel_path);
+    }
+    let (main_repo_path, _) = get_git_common_dir(worktree)?;
+    exclude_paths(&main_repo_path, &[&rel_path.to_string()])
+}
+
+fn agent_co_author(agent: &str) -> Result<&'static str> {
+    match agent {
+        "gemini" => Ok("Gemini <gemini@google.com>"),
+        "claude" => Ok("Claude <noreply@anthropic.com>"),
+        _ => bail!("Unknown agent '{}'. Use 'claude' or 'gemini'.", agent),
+    }
+}
+
+fn agent_prompt(issue_id: &str, co_author: &str, fuzemill_opens_pr: bool, backend: IssueBackend) -> String {
+    let done_cmd = format!("{} done", fuzemill_command(backend));
+
+    let issue_view_cmd = match backend {
+        IssueBackend::Beads => format!("bd show {}", issue_id),
+        IssueBackend::GitHub => format!("gh issue view {}", issue_id),
+    };
+
+    let task = if fuzemill_opens_pr {
+        format!("Your task is to fix this issue and commit the changes. Do not push or open a PR yourself; '{}' pushes the branch and opens the PR.", done_cmd)
+    } else {
+        "Your task is to fix this issue, commit the changes, push, and open a PR.".to_string()
+    };
+
+    format!(
+        "You are working on issue {}. Please call '{}' to get the details of the issue. {} When committing, please include a descriptive message and add 'Co-authored-by: {}' to the commit message. When you are finished, run '{}' to close the session.",
+        issue_id, issue_view_cmd, task, co_author, done_cmd
+    )
+}
+
+// Runs the agent inside the tmux pane through 'fuzemill run-agent', so its exit code and
+// final output are recorded even though the pane (and its scrollback) goes away with it.
+fn wrap_agent_command(agent_cmd: &str, run_dir: &Path, backend: IssueBackend) -> String {
+    format!(
+        "{} run-agent --run-dir {} {}",
+        fuzemill_command(backend),
+        shell_quote(&run_dir.display().to_string()),
+        shell_quote(agent_cmd)
+    )
+}
+
+fn handle_run_agent(run_dir: PathBuf, tail_lines: usize, command: String, verbose: bool, backend: IssueBackend) -> Result<()> {
+    if verbose {
+        println!("Starting agent (results in {})...", run_dir.display());
+    }
+
+    // Record everything the pane shows for auditing. There is no pane in the foreground.
+    let host = RunRecord::load(&run_dir)
+        .ok()
+        .and_then(|r| r.runner.host())
+        .filter(|h| *h != SessionHost::Foreground);
+    if let Some(host) = host
+        && let Err(e) = host.start_transcript(&run_dir.join("transcript.log"))
+    {
+        eprintln!("Warning: Failed to start transcript capture: {}", e);
+    }
+
+    // Commands run by the agent (e.g. 'fuzemill done') find their run through this
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .env("FUZEMILL_RUN_DIR", &run_dir)
+        .status()
+        .context("Failed to start the agent")?;
+
+    let code = status.code().or_else(|| status.signal().map(|s| 128 + s)).unwrap_or(1);
+
+    let mut run = RunRecord::load(&run_dir).unwrap_or_default();
+    run.finish(&env::current_dir()?, Some(code));
+    run.save(&run_dir)?;
+
+    let log_path = run_dir.join("agent.log");
+    let output = host
+        .and_then(|h| h.capture_tail(tail_lines))
+        .unwrap_or_else(|| "(no output captured: not running inside a terminal multiplexer)\n".to_string());
+    if let Err(e) = fs::write(&log_path, output) {
+        eprintln!("Warning: Failed to write {}: {}", log_path.display(), e);
+    }
+
+    if code != 0 && host.is_some() {
+        // Keep the pane open so the error stays on screen instead of the session vanishing
+        println!();
+        println!("{}", format!("Agent exited with code {}.", code).red().bold());
+        println!("The last {} lines of output were saved to {}", tail_lines, log_path.display());
+        println!("Press Enter to close this session.");
+        let _ = io::stdin().read_line(&mut String::new());
+    }
+
+    // tmux cleans up through its session-closed hook; the other hosts have none
+    if matches!(host, Some(SessionHost::Zellij | SessionHost::Screen))
+        && let Err(e) = schedule_cleanup(&env::current_dir()?, backend)
+    {
+        eprintln!("Warning: Failed to schedule the cleanup: {}", e);
+    }
+
+    if code != 0 {
+        std::process::exit(code);
+    }
+    Ok(())
+}
+
+fn last_lines(text: &str, count: usize) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    lines[lines.len().saturating_sub(count)..].join("\n")
+}
+
+// Missing when the session was ended by 'fuzemill done' or killed
+fn read_agent_exit_code(run_dir: &Path) -> Option<i32> {
+    RunRecord::load(run_dir).ok()?.exit_code
+}
+
+fn print_agent_failure(run_dir: &Path, code: i32) {
+    let log_path = run_dir.join("agent.log");
+    eprintln!("{}", format!("Agent exited with code {}. Not marking the issue in_progress.", code).red().bold());
+
+    if let Ok(log) = fs::read_to_string(&log_path) {
+        eprintln!("Last lines of output:");
+        for line in last_lines(log.trim_end(), 20).lines() {
+            eprintln!("  {}", line);
+        }
+    }
+    eprintln!("Full log: {}", log_path.display());
+}
+
+// Port of the HTTPS proxy inside a sandbox with a network allowlist
+const SANDBOX_PROXY_PORT: u16 = 3128;
+
+// What the sandbox mounts besides the system and the configured paths
+struct SandboxPaths {
+    worktree: PathBuf,
+    // Objects and refs the agent commits to, and fuzemill's state
+    common_dir: PathBuf,
+    // The worktree's own directory under <common>/worktrees; None for the main worktree
+    worktree_git_dir: Option<PathBuf>,
+    // The one writable part of fuzemill's state, see sandbox_outbox
+    outbox: PathBuf,
+    current_exe: PathBuf,
+}
+
+impl SandboxPaths {
+    // Mounted read-only over the writable worktree and common dir. Git runs the commands these
+    // point it to (hooks, core.fsmonitor, ...) outside the sandbox too, and fuzemill trusts its state.
+    fn read_only(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = ["config", "hooks", "info", "fuzemill"].iter().map(|name| self.common_dir.join(name)).collect();
+        if let Some(git_dir) = &self.worktree_git_dir {
+            paths.extend(["config.worktree", "commondir", "gitdir"].iter().map(|name| git_dir.join(name)));
+            paths.push(self.worktree.join(".git"));
+        }
+        paths
+    }
+}
+
+// Where a sandboxed 'done' leaves its mark, as the rest of the run directory is read-only
+fn sandbox_outbox(run_dir: &Path) -> PathBuf {
+    run_dir.join("sandbox")
+}
+
+// The agent command, run through 'fuzemill sandbox' when a sandbox is configured
+fn sandbox_command(git_root: &Path, agent_cmd: String, run_dir: &Path) -> Result<String> {
+    if load_config(git_root)?.sandbox.mode == SandboxMode::None {
+        return Ok(agent_cmd);
+    }
+    let current_exe = env::current_exe().unwrap_or_else(|_| PathBuf::from("fuzemill"));
+    Ok(format!(
+        "{} sandbox --run-dir {} {}",
+        shell_quote(&current_exe.display().to_string()),
+        shell_quote(&run_dir.display().to_string()),
+        shell_quote(&agent_cmd)
+    ))
+}
+
+// Runs the agent command in the configured sandbox, from the worktree. The agent can't end
+// its session from inside, so 'done' marks the run and the agent is stopped here.
+fn handle_sandbox(run_dir: PathBuf, inside: bool, command: String, verbose: bool) -> Result<()> {
+    if inside {
+        return run_inside_sandbox(&run_dir, &command);
+    }
+
+    let worktree = env::current_dir().context("Failed to get current directory")?;
+    let git_root = find_git_root(&worktree).context("Not in a git repository")?;
+    let config = load_config(&git_root)?.sandbox;
+    let paths = sandbox_paths(&git_root, &run_dir)?;
+    let current_exe = paths.current_exe.clone();
+
+    let mut inner = command.clone();
+    if config.network == SandboxNetwork::Allowlist {
+        start_sandbox_proxy(&run_dir, config.allow.clone())?;
+        inner = format!(
+            "{} sandbox --inside --run-dir {} {}",
+            shell_quote(&current_exe.display().to_string()),
+            shell_quote(&run_dir.display().to_string()),
+            shell_quote(&command)
+        );
+    }
+
+    let env_vars = sandbox_env(&config, &run_dir);
+    let container = format!("fuzemill-{}", std::process::id());
+    let mut cmd = match config.mode {
+        SandboxMode::Bwrap => bwrap_command(&config, &paths, &env_vars, &inner),
+        SandboxMode::Podman => podman_command(&config, &container, &paths, &env_vars, &inner)?,
+        SandboxMode::None => bail!("No sandbox is configured."),
+    };
+
+    if verbose {
+        println!("Running the agent in a {:?} sandbox...", config.mode);
+    }
+    let mut child = cmd.spawn().with_context(|| format!("Failed to start the {:?} sandbox", config.mode))?;
+
+    let status = loop {
+        if let Some(status) = child.try_wait().context("Failed to wait for the sandbox")? {
+            break Some(status);
+        }
+        if paths.outbox.join("done").exists() {
+            if verbose {
+                println!("The agent ran 'done'; stopping it...");
+            }
+            if config.mode == SandboxMode::Podman {
+                let _ = Command::new("podman")
+                    .arg("stop")
+                    .arg("--time")
+                    .arg("2")
+                    .arg(&container)
+                    .output();
+            }
+            let _ = child.kill();
+            let _ = child.wait();
+            break None;
+        }
+        thread::sleep(Duration::from_millis(500));
+    };
+    let _ = fs::remove_file(run_dir.join("proxy.sock"));
+
+    if paths.outbox.join("done").exists()
+        && let Ok(mut run) = RunRecord::load(&run_dir)
+    {
+        run.done = true;
+        if let Err(e) = run.save(&run_dir) {
+            eprintln!("Warning: Failed to record end of run: {}", e);
+        }
+    }
+
+    let Some(status) = status else {
+        return Ok(());
+    };
+    let code = status.code().or_else(|| status.signal().map(|s| 128 + s)).unwrap_or(1);
+    if code != 0 {
+        std::process::exit(code);
+    }
+    Ok(())
+}
+
+// Also creates what has to be there to be mounted read-only, so the agent can't create it instead
+fn sandbox_paths(git_root: &Path, run_dir: &Path) -> Result<SandboxPaths> {
+    let common_dir = state_dir(git_root)?.parent().context("Invalid git common dir")?.to_path_buf();
+    let output = Command::new("git")
+        .arg("rev-parse")
+        .arg("--absolute-git-dir")
+        .current_dir(git_root)
+        .output()
+        .context("Failed to execute 'git rev-parse'")?;
+    if !output.status.success() {
+        bail!("git rev-parse --absolute-git-dir failed");
+    }
+    let git_dir = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+    let worktree_git_dir = (git_dir != common_dir).then_some(git_dir);
+
+    for dir in [common_dir.join("hooks"), common_dir.join("info")] {
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    if let Some(git_dir) = &worktree_git_dir {
+        let config = git_dir.join("config.worktree");
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config)
+            .with_context(|| format!("Failed to create {}", config.display()))?;
+    }
+    let outbox = sandbox_outbox(run_dir);
+    let _ = fs::remove_dir_all(&outbox);
+    fs::create_dir_all(&outbox).with_context(|| format!("Failed to create {}", outbox.display()))?;
+
+    Ok(SandboxPaths {
+        worktree: git_root.to_path_buf(),
+        common_dir,
+        worktree_git_dir,
+        outbox,
+        current_exe: env::current_exe().context("Failed to find the fuzemill executable")?,
+    })
+}
+
+fn bwrap_command(config: &SandboxConfig, paths: &SandboxPaths, env_vars: &[(String, String)], inner: &str) -> Command {
+    let mut cmd = Command::new("bwrap");
+    cmd.arg("--die-with-parent")
+        .arg("--unshare-all");
+    if config.network == SandboxNetwork::Host {
+        cmd.arg("--share-net");
+    }
+    // Without a terminal there is no job control to keep, and a new session blocks TIOCSTI
+    if !io::stdin().is_terminal() {
+        cmd.arg("--new-session");
+    }
+
+    // The system toolchain, read-only
+    for dir in ["/usr", "/bin", "/sbin", "/lib", "/lib32", "/lib64", "/etc", "/opt", "/nix"] {
+        cmd.arg("--ro-bind-try").arg(dir).arg(dir);
+    }
+    cmd.arg("--proc")
+        .arg("/proc")
+        .arg("--dev")
+        .arg("/dev")
+        .arg("--tmpfs")
+        .arg("/tmp");
+
+    // An empty home with only the configured paths of the real one
+    if let Ok(home) = env::var("HOME") {
+        cmd.arg("--tmpfs").arg(home);
+    }
+    for path in config.read_only.iter().map(|p| expand_home(p)) {
+        cmd.arg("--ro-bind-try").arg(&path).arg(&path);
+    }
+    for path in config.writable.iter().map(|p| expand_home(p)) {
+        cmd.arg("--bind-try").arg(&path).arg(&path);
+    }
+
+    cmd.arg("--bind")
+        .arg(&paths.common_dir)
+        .arg(&paths.common_dir)
+        .arg("--bind")
+        .arg(&paths.worktree)
+        .arg(&paths.worktree);
+    for path in paths.read_only() {
+        cmd.arg("--ro-bind").arg(&path).arg(&path);
+    }
+    cmd.arg("--bind")
+        .arg(&paths.outbox)
+        .arg(&paths.outbox)
+        .arg("--ro-bind")
+        .arg(&paths.current_exe)
+        .arg(&paths.current_exe)
+        .arg("--chdir")
+        .arg(&paths.worktree)
+        .arg("--clearenv");
+    for (key, value) in env_vars {
+        cmd.arg("--setenv").arg(key).arg(value);
+    }
+    cmd.arg("--")
+        .arg("sh")
+        .arg("-c")
+        .arg(inner);
+    cmd
+}
+
+// The image provides the toolchain; fuzemill itself is mounted in, so it has to be able to run it
+fn podman_command(config: &SandboxConfig, container: &str, paths: &SandboxPaths, env_vars: &[(String, String)], inner: &str) -> Result<Command> {
+    let image = config.image.as_deref().context("Set 'image' under [sandbox] in .fuzemill.toml to use podman")?;
+
+    let mut cmd = Command::new("podman");
+    cmd.arg("run")
+        .arg("--rm")
+        .arg("--interactive")
+        .arg("--name")
+        .arg(container)
+        .arg("--userns=keep-id")
+        .arg("--security-opt")
+        .arg("no-new-privileges");
+    if io::stdin().is_terminal() {
+        cmd.arg("--tty");
+    }
+    if config.network != SandboxNetwork::Host {
+        cmd.arg("--network=none");
+    }
+
+    let mut volume = |from: &Path, options: &str| {
+        cmd.arg("--volume").arg(format!("{}:{}{}", from.display(), from.display(), options));
+    };
+    volume(&paths.common_dir, "");
+    volume(&paths.worktree, "");
+    for path in paths.read_only() {
+        volume(&path, ":ro");
+    }
+    volume(&paths.outbox, "");
+    volume(&paths.current_exe, ":ro");
+    // podman fails on missing paths, unlike bwrap's --bind-try
+    for path in config.read_only.iter().map(|p| expand_home(p)).filter(|p| p.exists()) {
+        volume(&path, ":ro");
+    }
+    for path in config.writable.iter().map(|p| expand_home(p)).filter(|p| p.exists()) {
+        volume(&path, "");
+    }
+
+    cmd.arg("--workdir").arg(&paths.worktree);
+    // The image has its own PATH
+    for (key, value) in env_vars.iter().filter(|(k, _)| k != "PATH") {
+        cmd.arg("--env").arg(format!("{}={}", key, value));
+    }
+    cmd.arg(image)
+        .arg("sh")
+        .arg("-c")
+        .arg(inner);
+    Ok(cmd)
+}
+
+// Only the basics and the configured variables get into the sandbox
+fn sandbox_env(config: &SandboxConfig, run_dir: &Path) -> Vec<(String, String)> {
+    let basics = ["PATH", "HOME", "USER", "LOGNAME", "TERM", "COLORTERM", "LANG", "LC_ALL", "TZ"];
+    let mut vars: Vec<(String, String)> = basics
+        .iter()
+        .map(|k| k.to_string())
+        .chain(config.env.iter().cloned())
+        .filter_map(|k| env::var(&k).ok().map(|v| (k, v)))
+        .collect();
+
+    vars.push(("FUZEMILL_RUN_DIR".to_string(), run_dir.display().to_string()));
+    vars.push(("FUZEMILL_SANDBOX".to_string(), "1".to_string()));
+    if config.network == SandboxNetwork::Allowlist {
+        let proxy = format!("http://127.0.0.1:{}", SANDBOX_PROXY_PORT);
+        for key in ["HTTPS_PROXY", "HTTP_PROXY", "https_proxy", "http_proxy"] {
+            vars.push((key.to_string(), proxy.clone()));
+        }
+        for key in ["NO_PROXY", "no_proxy"] {
+            vars.push((key.to_string(), "localhost,127.0.0.1".to_string()));
+        }
+    }
+    vars
+}
+
+// '~/' in configured paths is the home directory
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), env::var("HOME")) {
+        (Some(rest), Ok(home)) => Path::new(&home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+// The sandbox only has a loopback interface. The agent's HTTPS proxy forwards through a socket
+// in the run directory to this proxy, which only opens tunnels to allowed hosts. Every request
+// is logged to network.log.
+fn start_sandbox_proxy(run_dir: &Path, allow: Vec<String>) -> Result<()> {
+    let socket = run_dir.join("proxy.sock");
+    let _ = fs::remove_file(&socket);
+    let listener = UnixListener::bind(&socket).with_context(|| format!("Failed to listen on {}", socket.display()))?;
+    let log_path = run_dir.join("network.log");
+
+    thread::spawn(move || {
+        for client in listener.incoming().flatten() {
+            let allow = allow.clone();
+            let log_path = log_path.clone();
+            thread::spawn(move || {
+                let _ = proxy_connection(client, &allow, &log_path);
+            });
+        }
+    });
+    Ok(())
+}
+
+// Handles one HTTP CONNECT request; plain HTTP requests are refused
+fn proxy_connection(mut client: UnixStream, allow: &[String], log_path: &Path) -> io::Result<()> {
+    // Byte by byte, so nothing after the request head is consumed
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() > 8192 || client.read(&mut byte)? == 0 {
+            return Ok(());
+        }
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut parts = head.split_whitespace();
+    let (Some("CONNECT"), Some(target)) = (parts.next(), parts.next()) else {
+        return client.write_all(b"HTTP/1.1 405 Method Not Allowed\r\n\r\n");
+    };
+
+    let host = target.rsplit_once(':').map(|(host, _)| host).unwrap_or(target);
+    let allowed = host_allowed(host, allow);
+    let entry = format!("{} {} {}\n", utc_timestamp(unix_now()), if allowed { "allowed" } else { "blocked" }, target);
+    if let Ok(mut log) = fs::OpenOptions::new().create(true).append(true).open(log_path) {
+        let _ = log.write_all(entry.as_bytes());
+    }
+    if !allowed {
+        return client.write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n");
+    }
+
+    let upstream = match TcpStream::connect(target) {
+        Ok(upstream) => upstream,
+        Err(_) => return client.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n"),
+    };
+    client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")?;
+    forward_streams(client, upstream)
+}
+
+// Entries match the host exactly; '*.example.com' matches its subdomains
+fn host_allowed(host: &str, allow: &[String]) -> bool {
+    let host = host.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase();
+    allow.iter().any(|entry| {
+        let entry = entry.to_ascii_lowercase();
+        match entry.strip_prefix("*.") {
+            Some(domain) => host.ends_with(&format!(".{}", domain)),
+            None => host == entry,
+        }
+    })
+}
+
+// Copies in both directions until both sides are done
+fn forward_streams(unix: UnixStream, tcp: TcpStream) -> io::Result<()> {
+    let mut unix_read = unix.try_clone()?;
+    let mut tcp_write = tcp.try_clone()?;
+    let outgoing = thread::spawn(move || {
+        let _ = io::copy(&mut unix_read, &mut tcp_write);
+        let _ = tcp_write.shutdown(Shutdown::Write);
+    });
+
+    let (mut tcp_read, mut unix_write) = (tcp, unix);
+    let _ = io::copy(&mut tcp_read, &mut unix_write);
+    let _ = unix_write.shutdown(Shutdown::Write);
+    let _ = outgoing.join();
+    Ok(())
+}
+
+// Inside the sandbox: serves the proxy port by forwarding to the proxy socket outside, then runs the agent
+fn run_inside_sandbox(run_dir: &Path, command: &str) -> Result<()> {
+    let socket = run_dir.join("proxy.sock");
+    let listener = TcpListener::bind(("127.0.0.1", SANDBOX_PROXY_PORT)).context("Failed to listen on the sandbox proxy port")?;
+    thread::spawn(move || {
+        for client in listener.incoming().flatten() {
+            let socket = socket.clone();
+            thread::spawn(move || {
+                if let Ok(upstream) = UnixStream::connect(&socket) {
+                    let _ = forward_streams(upstream, client);
+                }
+            });
+        }
+    });
+
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .status()
+        .context("Failed to start the agent")?;
+    let code = status.code().or_else(|| status.signal().map(|s| 128 + s)).unwrap_or(1);
+    if code != 0 {
+        std::process::exit(code);
+    }
+    Ok(())
+}
+
+fn handle_logs(issue_id: String, list: bool, run: Option<String>, raw: bool, comment: Option<String>, verbose: bool, backend: IssueBackend) -> Result<()> {
+    let current_dir = env::current_dir().context("Failed to get current directory")?;
+    let git_root = find_git_root(&current_dir).context("Not in a git repository")?;
+
+    let runs = list_runs(&git_root, &issue_id)?;
+    if runs.is_empty() {
+        bail!("No recorded runs for issue '{}'.", issue_id);
+    }
+
+    if list {
+        for run_dir in &runs {
+            let run_id = run_dir.file_name().and_then(|n| n.to_str()).unwrap_or("?");
+            match RunRecord::load(run_dir) {
+                Ok(record) => println!(
+                    "{}  {} {}  {} -> {}  commits {}  exit {}",
+                    run_id.bold(),
+                    record.agent,
+                    record.model.as_deref().unwrap_or("(default model)"),
+                    record.started_at,
+                    record.ended_at.as_deref().unwrap_or("running"),
+                    record.commit_range().unwrap_or_else(|| "-".to_string()),
+                    record.exit_code.map(|c| c.to_string()).unwrap_or_else(|| "-".to_string())
+                ),
+                Err(_) => println!("{}  (no metadata)", run_id.bold()),
+            }
+        }
+        return Ok(());
+    }
+
+    let run_dir = match run {
+        Some(run_id) => runs
+            .iter()
+            .find(|p| p.file_name().and_then(|n| n.to_str()) == Some(run_id.as_str()))
+            .with_context(|| format!("Run '{}' not found. Use --list to see the recorded runs.", run_id))?,
+        None => runs.last().context("No recorded runs")?,
+    };
+
+    let transcript_path = run_dir.join("transcript.log");
+    let transcript = fs::read(&transcript_path).with_context(|| format!("No transcript found at {}", transcript_path.display()))?;
+    let transcript = String::from_utf8_lossy(&transcript);
+    let record = RunRecord::load(run_dir).unwrap_or_default();
+
+    if let Some(target) = comment {
+        let body = transcript_comment(run_dir, &record, &transcript);
+        return post_transcript_comment(&git_root, &issue_id, &target, &body, verbose, backend);
+    }
+
+    if raw {
+        print!("{}", transcript);
+    } else {
+        println!(
+            "{} {} {} ({} -> {})",
+            "Run".bold(),
+            run_dir.file_name().and_then(|n| n.to_str()).unwrap_or("?").bold(),
+            record.agent,
+            record.started_at,
+            record.ended_at.as_deref().unwrap_or("running")
+        );
+        println!("{}", condense_transcript(&transcript, usize::MAX));
+    }
+    Ok(())
+}
+
+// GitHub rejects comments over 65536 characters
+const MAX_COMMENT_CHARS: usize = 60_000;
+
+fn transcript_comment(run_dir: &Path, record: &RunRecord, transcript: &str) -> String {
+    let run_id = run_dir.file_name().and_then(|n| n.to_str()).unwrap_or("?");
+    let mut condensed = condense_transcript(transcript, 500);
+    if condensed.len() > MAX_COMMENT_CHARS {
+        let mut cut = condensed.len() - MAX_COMMENT_CHARS;
+        while !condensed.is_char_boundary(cut) {
+            cut += 1;
+        }
+        condensed = format!("[... earlier output truncated ...]\n{}", &condensed[cut..]);
+    }
+
+    format!(
+        "<details>\n<summary>fuzemill transcript: run {} ({}, {}, {} to {}, commits {})</summary>\n\n```text\n{}\n```\n</details>\n",
+        run_id,
+        record.agent,
+        record.model.as_deref().unwrap_or("default model"),
+        record.started_at,
+        record.ended_at.as_deref().unwrap_or("still running"),
+        record.commit_range().unwrap_or_else(|| "none".to_string()),
+        condensed.replace("```", "'''")
+    )
+}
+
+fn post_transcript_comment(cwd: &Path, issue_id: &str, target: &str, body: &str, verbose: bool, backend: IssueBackend) -> Result<()> {
+    let mut command = Command::new("gh");
+    match (target, backend) {
+        ("pr", _) => {
+            command.arg("pr").arg("comment").arg(issue_id);
+        }
+        ("issue", IssueBackend::GitHub) => {
+            command.arg("issue").arg("comment").arg(issue_id);
+        }
+        ("issue", IssueBackend::Beads) => bail!("Commenting on beads issues is not supported. Use '--comment pr'."),
+        _ => bail!("Unknown comment target '{}'. Use 'issue' or 'pr'.", target),
+    }
+    command.arg("--body-file").arg("-").current_dir(cwd);
+
+    if verbose {
+        println!("Posting transcript to {} {}...", target, issue_id);
+    }
+
+    let output = output_with_stdin(command, body).context("Failed to execute 'gh' to post the comment")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("Failed to post comment: {}", stderr.trim());
+    }
+
+    println!("Posted transcript to {} {}.", target, issue_id);
+    Ok(())
+}
+
+fn output_with_stdin(mut command: Command, input: &str) -> io::Result<Output> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.as_bytes())?;
+    }
+    child.wait_with_output()
+}
+
+// Turns a raw terminal recording into plain text: drops escape sequences, keeps only the
+// final state of lines redrawn with carriage returns, and collapses repeats and blank runs.
+fn condense_transcript(raw: &str, max_lines: usize) -> String {
+    let text = strip_ansi(raw);
+    let mut lines: Vec<&str> = Vec::new();
+
+    for line in text.split('\n') {
+        let line = line.trim_end_matches('\r');
+        let line = line.rsplit('\r').next().unwrap_or(line).trim_end();
+        let last = lines.last().copied();
+        if last == Some(line) || (line.is_empty() && last.is_none()) {
+            continue;
+        }
+        lines.push(line);
+    }
+
+    while lines.last() == Some(&"") {
+        lines.pop();
+    }
+    lines[lines.len().saturating_sub(max_lines)..].join("\n")
+}
+
+fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => match chars.next() {
+                // CSI: ESC [ params final-byte
+                Some('[') => {
+                    for c in chars.by_ref() {
+                        if ('@'..='~').contains(&c) {
+                            break;
+                        }
+                    }
+                }
+                // OSC: ESC ] ... terminated by BEL or ESC \
+                Some(']') => {
+                    while let Some(c) = chars.next() {
+                        if c == '\x07' || (c == '\x1b' && chars.next_if_eq(&'\\').is_some()) {
+                            break;
+                        }
+                    }
+                }
+                _ => {}
+            },
+            '\n' | '\r' | '\t' => out.push(c),
+            c if c.is_control() => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// Pushes the branch checked out in the worktree and opens its PR from the configured template.
+// If the PR already exists, only the push happens. Returns the PR URL, or None if there was
+// nothing to open.
+fn ensure_pr(worktree_path: &Path, verbose: bool, backend: IssueBackend) -> Result<Option<String>> {
+    let config = load_config(worktree_path)?;
+    if !config.pr.create {
+        return Ok(None);
+    }
+
+    let branch = get_current_branch(worktree_path)?;
+    let runs = list_runs(worktree_path, &branch)?;
+    let first_run = runs.first().and_then(|r| RunRecord::load(r).ok());
+    let last_run = runs.last().and_then(|r| RunRecord::load(r).ok());
+
+    // The branch is named after the issue, except for race candidates (<issue>-<agent>)
+    let issue_id = last_run.as_ref().map(|r| r.issue_id.clone()).unwrap_or_else(|| branch.clone());
+    let issue_id = issue_id.as_str();
+
+    // Commits since the worktree was first created for this issue
+    let base = first_run
+        .and_then(|r| r.base_commit)
+        .unwrap_or_else(|| "origin/HEAD".to_string());
+    let commits = commit_summary(worktree_path, &base)?;
+    if commits.is_empty() {
+        println!("No new commits on '{}', not opening a PR.", branch);
+        return Ok(None);
+    }
+
+    if verbose {
+        println!("Pushing '{}' to origin...", branch);
+    }
+    let output = worktree_git(worktree_path)?
+        .arg("push")
+        .arg("-u")
+        .arg("origin")
+        .arg(&branch)
+        .output()
+        .context("Failed to execute 'git push'")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("git push failed: {}", stderr.trim());
+    }
+
+    let mut issue = IssueRecord::load(worktree_path, &branch)?;
+
+    let existing = Command::new("gh")
+        .arg("pr")
+        .arg("view")
+        .arg(&branch)
+        .arg("--json")
+        .arg("url")
+        .arg("--jq")
+        .arg(".url")
+        .current_dir(worktree_path)
+        .output()
+        .context("Failed to execute 'gh pr view'")?;
+
+    if existing.status.success() {
+        let url = String::from_utf8_lossy(&existing.stdout).trim().to_string();
+        println!("Pushed to existing PR: {}", url);
+        issue.pr_url = Some(url.clone());
+        issue.save(worktree_path, &branch)?;
+        return Ok(Some(url));
+    }
+
+    let (agent, model) = match &last_run {
+        Some(run) => (run.agent.clone(), run.model.clone().unwrap_or_else(|| "default model".to_string())),
+        None => ("unknown agent".to_string(), "default model".to_string()),
+    };
+    let title = fetch_issue_title(worktree_path, issue_id, backend)
+        .or_else(|| commits.lines().next().map(|l| l.trim_start_matches("- ").to_string()))
+        .unwrap_or_else(|| issue_id.to_string());
+    let closes = match backend {
+        IssueBackend::GitHub => format!("Closes #{}", issue_id),
+        IssueBackend::Beads => format!("Issue: {}", issue_id),
+    };
+    let checks = last_run.as_ref().map(|r| checks_summary(&r.checks)).unwrap_or_default();
+    let vars = [
+        ("issue", issue_id),
+        ("title", title.as_str()),
+        ("closes", closes.as_str()),
+        ("agent", agent.as_str()),
+        ("model", model.as_str()),
+        ("commits", commits.as_str()),
+        ("branch", branch.as_str()),
+        ("checks", checks.as_str()),
+    ];
+
+    let mut command = Command::new("gh");
+    command
+        .arg("pr")
+        .arg("create")
+        .arg("--head")
+        .arg(&branch)
+        .arg("--title")
+        .arg(render_template(&config.pr.title, &vars))
+        .arg("--body-file")
+        .arg("-")
+        .current_dir(worktree_path);
+    if config.pr.draft {
+        command.arg("--draft");
+    }
+    if let Some(base) = &issue.base {
+        command.arg("--base").arg(base);
+    }
+
+    if verbose {
+        println!("Opening PR for '{}'...", branch);
+    }
+    let output = output_with_stdin(command, &render_template(&config.pr.body, &vars)).context("Failed to execute 'gh pr create'")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("gh pr create failed: {}", stderr.trim());
+    }
+
+    let url = String::from_utf8_lossy(&output.stdout).lines().last().unwrap_or("").trim().to_string();
+    println!("Opened PR: {}", url.green());
+    issue.pr_url = Some(url.clone());
+    issue.save(worktree_path, &branch)?;
+    Ok(Some(url))
+}
+
+// Records how an issue's work ended, for 'report'. Only issues fuzemill has state for are recorded.
+fn set_issue_outcome(git_root: &Path, branch: &str, outcome: &str) {
+    let has_state = issue_state_dir(git_root, branch).is_ok_and(|d| d.exists());
+    if !has_state {
+        return;
+    }
+    let result = IssueRecord::load(git_root, branch).and_then(|mut issue| {
+        issue.outcome = Some(outcome.to_string());
+        issue.save(git_root, branch)
+    });
+    if let Err(e) = result {
+        eprintln!("Warning: Failed to record the outcome of {}: {}", branch, e);
+    }
+}
+
+// Runs the configured checks in the worktree unless they already passed on the current commit.
+// Results are recorded in the run's record. Returns whether all checks passed.
+fn verify_checks(worktree_path: &Path, run_dir: Option<&Path>, verbose: bool) -> Result<bool> {
+    let checks = load_config(worktree_path)?.checks;
+    if checks.is_empty() {
+        return Ok(true);
+    }
+
+    let mut run = run_dir.and_then(|d| RunRecord::load(d).ok());
+    let head = git_head(worktree_path);
+    if let Some(run) = &run
+        && run.checked_commit.is_some()
+        && run.checked_commit == head
+        && !run.checks.is_empty()
+        && run.checks.iter().all(|c| c.passed())
+        && !worktree_has_changes(worktree_path)?
+    {
+        if verbose {
+            println!("Checks already passed on this commit.");
+        }
+        return Ok(true);
+    }
+
+    let log_path = run_dir.map(|d| d.join("checks.log"));
+    let mut log = String::new();
+    let mut results = Vec::new();
+    for check in &checks {
+        println!("Running check '{}'...", check.name());
+        let started = Instant::now();
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(&check.run)
+            .current_dir(worktree_path)
+            .output()
+            .with_context(|| format!("Failed to execute '{}'", check.run))?;
+
+        let mut text = String::from_utf8_lossy(&output.stdout).to_string();
+        text.push_str(&String::from_utf8_lossy(&output.stderr));
+        let result = CheckResult {
+            name: check.name().to_string(),
+            command: check.run.clone(),
+            exit_code: output.status.code(),
+            duration_secs: started.elapsed().as_secs(),
+        };
+
+        if result.passed() {
+            println!("{}", format!("Check '{}' passed.", result.name).green());
+        } else {
+            println!("{}", format!("Check '{}' failed ({}):", result.name, describe_exit(output.status.code())).red());
+            if !text.trim().is_empty() {
+                println!("{}", last_lines(text.trim_end(), 100));
+            }
+        }
+
+        log.push_str(&format!("==> {} ({}): {}\n{}\n", result.name, result.command, describe_exit(result.exit_code), text));
+        results.push(result);
+    }
+
+    if let Some(path) = &log_path
+        && let Err(e) = fs::write(path, &log)
+    {
+        eprintln!("Warning: Failed to write {}: {}", path.display(), e);
+    }
+
+    let passed = results.iter().all(|r| r.passed());
+    if let (Some(run), Some(run_dir)) = (run.as_mut(), run_dir) {
+        run.checks = results;
+        run.checked_commit = head;
+        if let Err(e) = run.save(run_dir) {
+            eprintln!("Warning: Failed to record check results: {}", e);
+        }
+    }
+    Ok(passed)
+}
+
+fn describe_exit(code: Option<i32>) -> String {
+    match code {
+        Some(0) => "passed".to_string(),
+        Some(code) => format!("exit code {}", code),
+        None => "killed by a signal".to_string(),
+    }
+}
+
+// "## Checks" section for the PR body; empty if no checks ran
+fn checks_summary(checks: &[CheckResult]) -> String {
+    if checks.is_empty() {
+        return String::new();
+    }
+    let mut out = String::from("## Checks\n\n");
+    for check in checks {
+        let status = if check.passed() { "passed".to_string() } else { describe_exit(check.exit_code) };
+        out.push_str(&format!("- `{}`: {} ({}s)\n", check.command, status, check.duration_secs));
+    }
+    out.push('\n');
+    out
+}
+
+// One "- subject (sha)" line per commit in base..HEAD, oldest first
+fn commit_summary(cwd: &Path, base: &str) -> Result<String> {
+    let output = Command::new("git")
+        .arg("log")
+        .arg("--reverse")
+        .arg("--format=- %s (%h)")
+        .arg(format!("{}..HEAD", base))
+        .current_dir(cwd)
+        .output()
+        .context("Failed to execute 'git log'")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("git log failed: {}", stderr.trim());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim_end().to_string())
+}
+
+fn fetch_issue_title(cwd: &Path, issue_id: &str, backend: IssueBackend) -> Option<String> {
+    let output = match backend {
+        IssueBackend::GitHub => Command::new("gh")
+            .arg("issue")
+            .arg("view")
+            .arg(issue_id)
+            .arg("--json")
+            .arg("title")
+            .current_dir(cwd)
+            .output()
+            .ok()?,
+        IssueBackend::Beads => Command::new("bd")
+            .arg("show")
+            .arg(issue_id)
+            .arg("--json")
+            .current_dir(cwd)
+            .output()
+            .ok()?,
+    };
+
+    if !output.status.success() {
+        return None;
+    }
+
+    // 'bd show --json' may return a list with a single issue
+    let value: serde_json::Value = serde_json::from_slice(&output.stdout).ok()?;
+    let issue = value.as_array().and_then(|a| a.first()).unwrap_or(&value);
+    issue.get("title")?.as_str().map(|t| t.to_string())
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PullRequest {
+    number: u64,
+    url: String,
+    head_ref_name: String,
+    head_ref_oid: String,
+}
+
+struct ReviewComment {
+    author: String,
+    body: String,
+}
+
+struct ReviewThread {
+    location: String,
+    comments: Vec<ReviewComment>,
+}
+
+struct FailingCheck {
+    name: String,
+    link: String,
+    log: Option<String>,
+}
+
+fn handle_revise(issue_id: String, model: Option<String>, agent: String, verbose: bool, backend: IssueBackend) -> Result<()> {
+    let current_dir = env::current_dir().context("Failed to get current directory")?;
+    let git_root = find_git_root(&current_dir).context("Not in a git repository")?;
+
+    preflight(&git_root, &agent, verbose, backend)?;
+
+    let pr = fetch_pull_request(&git_root, &issue_id)?;
+    if verbose {
+        println!("Collecting feedback on {}...", pr.url);
+    }
+
+    let (threads, reviews) = fetch_review_feedback(&git_root, pr.number)?;
+    let failing = fetch_failing_checks(&git_root, &pr.head_ref_name, verbose)?;
+
+    if threads.is_empty() && reviews.is_empty() && failing.is_empty() {
+        println!("No unresolved review threads or failing checks on {}.", pr.url);
+        return Ok(());
+    }
+    println!(
+        "Found {} unresolved thread(s), {} review comment(s) and {} failing check(s).",
+        threads.len(),
+        reviews.len(),
+        failing.len()
+    );
+
+    let worktree = prepare_pr_worktree(&git_root, &issue_id, &pr.head_ref_name, verbose)?;
+
+    let config = load_config(&git_root)?;
+    let feedback = format_feedback(&threads, &reviews, &failing);
+    let session = AgentSession {
+        prompt: pr_followup_prompt(
+            &issue_id,
+            &pr,
+            "The review feedback and failing checks are listed below. Address each point and commit the changes.",
+            &feedback,
+            agent_co_author(&agent)?,
+            config.pr.create,
+            backend,
+        ),
+        branch: issue_id.clone(),
+        issue_id,
+        worktree,
+        agent,
+        model,
+        launch_hook: None,
+    };
+
+    println!("Launching {} session in {}", session.agent, session.worktree.display().to_string().green());
+    run_agent_session(&git_root, &session, verbose, backend)
+}
+
+fn fetch_pull_request(cwd: &Path, branch: &str) -> Result<PullRequest> {
+    let output = Command::new("gh")
+        .arg("pr")
+        .arg("view")
+        .arg(branch)
+        .arg("--json")
+        .arg("number,url,headRefName,headRefOid")
+        .current_dir(cwd)
+        .output()
+        .context("Failed to execute 'gh pr view'")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("No PR found for branch '{}': {}", branch, stderr.trim());
+    }
+
+    serde_json::from_slice(&output.stdout).context("Failed to parse 'gh pr view' output")
+}
+
+const REVIEW_THREADS_QUERY: &str = "query($owner: String!, $name: String!, $number: Int!) {
+  repository(owner: $owner, name: $name) {
+    pullRequest(number: $number) {
+      reviewThreads(first: 100) {
+        nodes {
+          isResolved
+          path
+          line
+          comments(first: 50) { nodes { author { login } body } }
+        }
+      }
+      reviews(last: 50) { nodes { state body author { login } } }
+    }
+  }
+}";
+
+// Returns the unresolved review threads and the top-level comments of reviews requesting changes
+fn fetch_review_feedback(cwd: &Path, pr_number: u64) -> Result<(Vec<ReviewThread>, Vec<ReviewComment>)> {
+    let output = Command::new("gh")
+        .arg("api")
+        .arg("graphql")
+        .arg("-F")
+        .arg("owner={owner}")
+        .arg("-F")
+        .arg("name={repo}")
+        .arg("-F")
+        .arg(format!("number={}", pr_number))
+        .arg("-f")
+        .arg(format!("query={}", REVIEW_THREADS_QUERY))
+        .current_dir(cwd)
+        .output()
+        .context("Failed to execute 'gh api graphql'")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("Failed to fetch review threads: {}", stderr.trim());
+    }
+
+    let value: serde_json::Value = serde_json::from_slice(&output.stdout).context("Failed to parse review threads")?;
+    let pr = &value["data"]["repository"]["pullRequest"];
+    let to_comment = |c: &serde_json::Value| ReviewComment {
+        author: c["author"]["login"].as_str().unwrap_or("ghost").to_string(),
+        body: c["body"].as_str().unwrap_or("").trim().to_string(),
+    };
+
+    let mut threads = Vec::new();
+    for thread in pr["reviewThreads"]["nodes"].as_array().into_iter().flatten() {
+        if thread["isResolved"].as_bool().unwrap_or(false) {
+            continue;
+        }
+        let path = thread["path"].as_str().unwrap_or("(unknown file)");
+        let location = match thread["line"].as_u64() {
+            Some(line) => format!("{}:{}", path, line),
+            None => path.to_string(),
+        };
+        let comments = thread["comments"]["nodes"].as_array().into_iter().flatten().map(to_comment).collect();
+        threads.push(ReviewThread { location, comments });
+    }
+
+    let reviews = pr["reviews"]["nodes"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|r| r["state"].as_str() == Some("CHANGES_REQUESTED"))
+        .map(to_comment)
+        .filter(|c| !c.body.is_empty())
+        .collect();
+
+    Ok((threads, reviews))
+}
+
+fn fetch_failing_checks(cwd: &Path, branch: &str, verbose: bool) -> Result<Vec<FailingCheck>> {
+    // 'gh pr checks' exits non-zero when checks fail or are pending, so only the output matters
+    let output = Command::new("gh")
+        .arg("pr")
+        .arg("checks")
+        .arg(branch)
+        .arg("--json")
+        .arg("name,bucket,link")
+        .current_dir(cwd)
+        .output()
+        .context("Failed to execute 'gh pr checks'")?;
+
+    let Ok(checks) = serde_json::from_slice::<serde_json::Value>(&output.stdout) else {
+        if verbose {
+            eprintln!("Warning: Could not read checks: {}", String::from_utf8_lossy(&output.stderr).trim());
+        }
+        return Ok(Vec::new());
+    };
+
+    let mut failing = Vec::new();
+    for check in checks.as_array().into_iter().flatten() {
+        if check["bucket"].as_str() != Some("fail") {
+            continue;
+        }
+        let name = check["name"].as_str().unwrap_or("(unnamed check)").to_string();
+        let link = check["link"].as_str().unwrap_or("").to_string();
+        if verbose {
+            println!("Fetching logs for failing check '{}'...", name);
+        }
+        let log = fetch_failed_job_log(cwd, &link).map(|log| trim_ci_log(&log, 150));
+        failing.push(FailingCheck { name, link, log });
+    }
+    Ok(failing)
+}
+
+// Only GitHub Actions checks have logs we can fetch; their links end in /runs/<run>/job/<job>
+fn fetch_failed_job_log(cwd: &Path, link: &str) -> Option<String> {
+    let job_id = link.split("/job/").nth(1)?.split(['/', '?', '#']).next()?;
+    let output = Command::new("gh")
+        .arg("run")
+        .arg("view")
+        .arg("--job")
+        .arg(job_id)
+        .arg("--log-failed")
+        .current_dir(cwd)
+        .output()
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+// Keeps the parts of a CI log around error lines (plus a little context), falling back to
+// the end of the log when nothing looks like an error.
+fn trim_ci_log(log: &str, max_lines: usize) -> String {
+    const CONTEXT: usize = 5;
+    const MARKERS: [&str; 8] = ["error", "failed", "failure", "panicked", "fail:", "exception", "traceback", "assert"];
+
+    // 'gh run view --log' prefixes each line with "<job>\t<step>\t<timestamp> "
+    let lines: Vec<String> = strip_ansi(log)
+        .lines()
+        .map(|line| {
+            let content = line.rsplit('\t').next().unwrap_or(line);
+            match content.split_once(' ') {
+                Some((ts, rest)) if ts.len() >= 20 && ts.ends_with('Z') && ts.contains('T') => rest.to_string(),
+                _ => content.to_string(),
+            }
+        })
+        .collect();
+
+    let mut keep = vec![false; lines.len()];
+    for (i, line) in lines.iter().enumerate() {
+        let lower = line.to_lowercase();
+        if MARKERS.iter().any(|m| lower.contains(m)) {
+            let end = (i + CONTEXT + 1).min(lines.len());
+            keep[i.saturating_sub(CONTEXT)..end].iter_mut().for_each(|k| *k = true);
+        }
+    }
+
+    let mut out: Vec<&str> = Vec::new();
+    if keep.iter().any(|k| *k) {
+        for (i, line) in lines.iter().enumerate() {
+            if keep[i] {
+                out.push(line);
+            } else if out.last() != Some(&"...") && !out.is_empty() {
+                out.push("...");
+            }
+        }
+    } else {
+        out = lines.iter().map(|l| l.as_str()).collect();
+    }
+
+    while out.last() == Some(&"...") {
+        out.pop();
+    }
+    out[out.len().saturating_sub(max_lines)..].join("\n")
+}
+
+fn format_feedback(threads: &[ReviewThread], reviews: &[ReviewComment], failing: &[FailingCheck]) -> String {
+    let mut out = String::new();
+
+    if !reviews.is_empty() {
+        out.push_str("## Review comments\n\n");
+        for review in reviews {
+            out.push_str(&format!("- @{}: {}\n", review.author, review.body));
+        }
+        out.push('\n');
+    }
+
+    if !threads.is_empty() {
+        out.push_str("## Unresolved review threads\n\n");
+        for thread in threads {
+            out.push_str(&format!("### {}\n\n", thread.location));
+            for comment in &thread.comments {
+                out.push_str(&format!("- @{}: {}\n", comment.author, comment.body));
+            }
+            out.push('\n');
+        }
+    }
+
+    if !failing.is_empty() {
+        out.push_str("## Failing checks\n\n");
+        for check in failing {
+            out.push_str(&format!("### {} ({})\n\n", check.name, check.link));
+            match &check.log {
+                Some(log) => out.push_str(&format!("```text\n{}\n```\n\n", log)),
+                None => out.push_str("(no log available)\n\n"),
+            }
+        }
+    }
+
+    out
+}
+
+// Prompt for follow-up work on an existing PR; `task` says what to do with `feedback`
+fn pr_followup_prompt(issue_id: &str, pr: &PullRequest, task: &str, feedback: &str, co_author: &str, fuzemill_opens_pr: bool, backend: IssueBackend) -> String {
+    let done_cmd = format!("{} done", fuzemill_command(backend));
+
+    let push = if fuzemill_opens_pr {
+        format!("Do not push yourself; '{}' pushes your commits to the existing PR.", done_cmd)
+    } else {
+        format!("Push your commits to the '{}' branch so they land on the existing PR; do not open a new PR.", pr.head_ref_name)
+    };
+
+    format!(
+        "You are working on PR #{} ({}) for issue {}, on branch '{}'. {} {} When committing, please include a descriptive message and add 'Co-authored-by: {}' to the commit message. When you are finished, run '{}' to close the session.\n\n{}",
+        pr.number, pr.url, issue_id, pr.head_ref_name, task, push, co_author, done_cmd, feedback
+    )
+}
+
+fn handle_fix_ci(issue_id: String, model: Option<String>, agent: String, watch: bool, max_attempts: u32, verbose: bool, backend: IssueBackend) -> Result<()> {
+    let current_dir = env::current_dir().context("Failed to get current directory")?;
+    let git_root = find_git_root(&current_dir).context("Not in a git repository")?;
+
+    preflight(&git_root, &agent, verbose, backend)?;
+
+    let session_name = format!("fuzemill-{}", issue_id);
+    let mut attempt = 1;
+
+    loop {
+        let pr = fetch_pull_request(&git_root, &issue_id)?;
+        let failing = fetch_failing_checks(&git_root, &pr.head_ref_name, verbose)?;
+
+        if failing.is_empty() {
+            if attempt == 1 {
+                println!("No failing checks on {}.", pr.url);
+            } else {
+                println!("{}", format!("All checks pass on {} after {} attempt(s).", pr.url, attempt - 1).green());
+            }
+            return Ok(());
+        }
+
+        if attempt > max_attempts {
+            bail!("Checks are still failing on {} after {} attempt(s).", pr.url, max_attempts);
+        }
+
+        let names: Vec<&str> = failing.iter().map(|c| c.name.as_str()).collect();
+        println!("Attempt {}/{}: fixing failing check(s): {}", attempt, max_attempts, names.join(", "));
+
+        let worktree = prepare_pr_worktree(&git_root, &issue_id, &pr.head_ref_name, verbose)?;
+        let config = load_config(&git_root)?;
+        let feedback = format_feedback(&[], &[], &failing);
+        let session = AgentSession {
+            prompt: pr_followup_prompt(
+                &issue_id,
+                &pr,
+                "CI is failing; the relevant parts of the failing job logs are listed below. Find the cause of each failure, fix it, and commit the changes.",
+                &feedback,
+                agent_co_author(&agent)?,
+                config.pr.create,
+                backend,
+            ),
+            issue_id: issue_id.clone(),
+            branch: issue_id.clone(),
+            worktree,
+            agent: agent.clone(),
+            model: model.clone(),
+            launch_hook: None,
+        };
+
+        println!("Launching {} session in {}", session.agent, session.worktree.display().to_string().green());
+        run_agent_session(&git_root, &session, verbose, backend)?;
+
+        if !watch {
+            return Ok(());
+        }
+
+        // The user may have detached; keep watching until the agent is done
+        let host = session_host(&git_root, &issue_id);
+        if host.is_alive(&session_name) {
+            println!("Waiting for session '{}' to finish...", session_name);
+            while host.is_alive(&session_name) {
+                thread::sleep(Duration::from_secs(10));
+            }
+        }
+
+        if !wait_for_pr_update(&git_root, &issue_id, &pr.head_ref_oid, verbose)? {
+            bail!("No new commits were pushed to {}; stopping.", pr.url);
+        }
+
+        println!("Waiting for checks on {}...", pr.url);
+        wait_for_checks(&git_root, &pr.head_ref_name, verbose)?;
+        attempt += 1;
+    }
+}
+
+// The push happens as the session finishes (possibly in the tmux hook), so give it a moment
+fn wait_for_pr_update(cwd: &Path, branch: &str, previous_head: &str, verbose: bool) -> Result<bool> {
+    for _ in 0..30 {
+        let pr = fetch_pull_request(cwd, branch)?;
+        if pr.head_ref_oid != previous_head {
+            if verbose {
+                println!("PR head moved to {}.", short_sha(&pr.head_ref_oid));
+            }
+            return Ok(true);
+        }
+        thread::sleep(Duration::from_secs(10));
+    }
+    Ok(false)
+}
+
+// Polls until no check is pending. Checks take a little while to register after a push.
+fn wait_for_checks(cwd: &Path, branch: &str, verbose: bool) -> Result<()> {
+    const POLL: Duration = Duration::from_secs(30);
+    const TIMEOUT: Duration = Duration::from_secs(2 * 60 * 60);
+
+    let started = Instant::now();
+    thread::sleep(POLL);
+
+    while started.elapsed() < TIMEOUT {
+        let output = Command::new("gh")
+            .arg("pr")
+            .arg("checks")
+            .arg(branch)
+            .arg("--json")
+            .arg("bucket")
+            .current_dir(cwd)
+            .output()
+            .context("Failed to execute 'gh pr checks'")?;
+
+        // Checks that haven't registered by now never will; there is nothing to wait for
+        if String::from_utf8_lossy(&output.stderr).contains("no checks reported") {
+            println!("No checks reported on '{}'.", branch);
+            return Ok(());
+        }
+        if let Ok(checks) = serde_json::from_slice::<serde_json::Value>(&output.stdout) {
+            let buckets: Vec<&str> = checks.as_array().into_iter().flatten().filter_map(|c| c["bucket"].as_str()).collect();
+            let pending = buckets.iter().filter(|b| **b == "pending").count();
+            if output.status.success() && buckets.is_empty() {
+                println!("No checks reported on '{}'.", branch);
+                return Ok(());
+            }
+            if pending == 0 {
+                return Ok(());
+            }
+            if verbose {
+                println!("{} of {} check(s) still pending...", pending, buckets.len());
+            }
+        }
+        thread::sleep(POLL);
+    }
+
+    bail!("Timed out waiting for checks on '{}'", branch)
+}
+
+// Reuses the issue's worktree if it still exists, otherwise recreates it on the PR branch
+fn prepare_pr_worktree(git_root: &Path, issue_id: &str, branch: &str, verbose: bool) -> Result<PathBuf> {
+    let worktree_path = issue_worktree_path(git_root, issue_id)?;
+    if worktree_path.exists() {
+        println!("Reusing worktree at {}", worktree_path.display());
+        return Ok(worktree_path);
+    }
+
+    let (main_repo_path, _) = get_git_common_dir(git_root)?;
+
+    if verbose {
+        println!("Fetching '{}' from origin...", branch);
+    }
+    let fetched = Command::new("git")
+        .arg("fetch")
+        .arg("origin")
+        .arg(branch)
+        .current_dir(&main_repo_path)
+        .status()
+        .context("Failed to execute 'git fetch'")?
+        .success();
+
+    let has_local_branch = Command::new("git")
+        .arg("rev-parse")
+        .arg("--verify")
+        .arg("--quiet")
+        .arg(format!("refs/heads/{}", branch))
+        .current_dir(&main_repo_path)
+        .output()
+        .map(|o| o.status.success())
+        .unwrap_or(false);
+
+    if verbose {
+        println!("Creating worktree at: {}", worktree_path.display());
+    }
+
+    let mut command = Command::new("git");
+    command.arg("worktree").arg("add");
+    if has_local_branch {
+        command.arg(&worktree_path).arg(branch);
+    } else {
+        command
+            .arg("--track")
+            .arg("-b")
+            .arg(branch)
+            .arg(&worktree_path)
+            .arg(format!("origin/{}", branch));
+    }
+    let status = command
+        .current_dir(&main_repo_path)
+        .status()
+        .context("Failed to execute git worktree add")?;
+
+    if !status.success() {
+        bail!("git worktree add failed");
+    }
+
+    // The local branch may be behind commits pushed to the PR since
+    if has_local_branch && fetched {
+        let status = Command::new("git")
+            .arg("merge")
+            .arg("--ff-only")
+            .arg(format!("origin/{}", branch))
+            .current_dir(&worktree_path)
+            .status()
+            .context("Failed to execute 'git merge'")?;
+        if !status.success() {
+            eprintln!("Warning: Local branch '{}' has diverged from origin; continuing with the local branch.", branch);
+        }
+    }
+
+    bootstrap_worktree(&worktree_path, verbose)?;
+    run_hook(git_root, "post-worktree", issue_id, branch, &worktree_path, verbose)?;
+    Ok(worktree_path)
+}
+
+// Starts one session per agent on the same issue, each on its own <issue>-<agent> branch and
+// worktree. The sessions run detached; 'compare' and 'pick' take it from there.
+fn start_race(git_root: &Path, config: &Config, issue_id: &str, agents: &[String], on: Option<(&str, &str)>, verbose: bool, backend: IssueBackend) -> Result<()> {
+    if let Err(e) = update_issue_status(git_root, issue_id, "hooked", verbose, backend) {
+        eprintln!("Warning: Failed to set issue status to 'hooked': {}", e);
+    }
+
+    let runner = config.resolve_runner()?;
+    let host = runner.host().unwrap_or(SessionHost::Tmux);
+    let mut sessions = Vec::new();
+    for agent in agents {
+        let branch = race_branch(issue_id, agent);
+        let worktree = issue_worktree_path(git_root, &branch)?;
+        run_hook(git_root, "pre-start", issue_id, &branch, &worktree, verbose)?;
+        create_worktree(&branch, &worktree, on.map(|(_, start_point)| start_point), verbose)?;
+        record_stack_base(git_root, &branch, on)?;
+        bootstrap_worktree(&worktree, verbose)?;
+        run_hook(git_root, "post-worktree", issue_id, &branch, &worktree, verbose)?;
+
+        println!("Launching {} session in {}", agent, worktree.display().to_string().green());
+        let session = AgentSession {
+            prompt: agent_prompt(issue_id, agent_co_author(agent)?, config.pr.create, backend),
+            issue_id: issue_id.to_string(),
+            branch: branch.clone(),
+            worktree,
+            agent: agent.clone(),
+            model: None,
+            launch_hook: Some("post-start"),
+        };
+        let session_name = format!("fuzemill-{}", branch);
+        launch_agent_session(git_root, runner, host, &session, &session_name, verbose, backend)?;
+        sessions.push(session_name);
+    }
+
+    let mut record = IssueRecord::load(git_root, issue_id)?;
+    record.candidates = agents.to_vec();
+    record.base = on.map(|(base, _)| base.to_string());
+    record.save(git_root, issue_id)?;
+
+    if let Err(e) = update_issue_status(git_root, issue_id, "in_progress", verbose, backend) {
+        eprintln!("Warning: Failed to set issue status to 'in_progress': {}", e);
+    }
+
+    println!("{}", format!("Racing {} on {}.", agents.join(", "), issue_id).green());
+    println!("Watch a session with:");
+    for session_name in &sessions {
+        println!("  {}", host.attach_command(session_name));
+    }
+    println!("Compare the results with 'fuzemill compare {}' and keep one with 'fuzemill pick {} <agent>'.", issue_id, issue_id);
+    Ok(())
+}
+
+fn race_branch(issue_id: &str, agent: &str) -> String {
+    format!("{}-{}", issue_id, agent)
+}
+
+fn race_candidates(git_root: &Path, issue_id: &str) -> Result<Vec<String>> {
+    let record = IssueRecord::load(git_root, issue_id)?;
+    if record.candidates.is_empty() {
+        bail!(
+            "No race is running for {}. Start one with 'fuzemill start --id {} --agent claude --agent gemini'.",
+            issue_id,
+            issue_id
+        );
+    }
+    Ok(record.candidates)
+}
+
+fn handle_compare(issue_id: String, test: Option<String>, verbose: bool) -> Result<()> {
+    let current_dir = env::current_dir().context("Failed to get current directory")?;
+    let git_root = find_git_root(&current_dir).context("Not in a git repository")?;
+    let (main_repo_path, _) = get_git_common_dir(&git_root)?;
+
+    for agent in race_candidates(&git_root, &issue_id)? {
+        let branch = race_branch(&issue_id, &agent);
+        let session_name = format!("fuzemill-{}", branch);
+        let runs = list_runs(&git_root, &branch)?;
+        let last_run = runs.last().and_then(|r| RunRecord::load(r).ok());
+        let running = session_host(&git_root, &branch).is_alive(&session_name);
+
+        println!("{}", format!("{} ({})", agent, branch).bold());
+
+        let state = match last_run.as_ref().and_then(|r| r.exit_code) {
+            _ if running => "running".yellow(),
+            Some(code) if code != 0 => format!("agent failed (exit code {})", code).red(),
+            _ => "finished".green(),
+        };
+        println!("  Session: {}", state);
+
+        let base = runs
+            .first()
+            .and_then(|r| RunRecord::load(r).ok())
+            .and_then(|r| r.base_commit)
+            .unwrap_or_else(|| "origin/HEAD".to_string());
+        let commits = Command::new("git")
+            .arg("rev-list")
+            .arg("--count")
+            .arg(format!("{}..{}", base, branch))
+            .current_dir(&main_repo_path)
+            .output()
+            .context("Failed to execute 'git rev-list'")?;
+        let diffstat = Command::new("git")
+            .arg("diff")
+            .arg("--shortstat")
+            .arg(&base)
+            .arg(&branch)
+            .current_dir(&main_repo_path)
+            .output()
+            .context("Failed to execute 'git diff'")?;
+        let diffstat = String::from_utf8_lossy(&diffstat.stdout).trim().to_string();
+        println!(
+            "  Changes: {} commit(s), {}",
+            String::from_utf8_lossy(&commits.stdout).trim(),
+            if diffstat.is_empty() { "no changes" } else { &diffstat }
+        );
+
+        if let Some(cmd) = &test {
+            let result = if running {
+                "skipped, the agent is still working".yellow()
+            } else {
+                match run_race_test(&git_root, &branch, cmd, verbose)? {
+                    (true, _) => "pass".green(),
+                    (false, tail) if tail.is_empty() => "fail".red(),
+                    (false, tail) => format!("fail\n{}", tail).red(),
+                }
+            };
+            println!("  Tests: {}", result);
+        }
+
+        println!("  CI: {}", race_ci_summary(&main_repo_path, &branch));
+        println!();
+    }
+
+    println!("Keep one with 'fuzemill pick {} <agent>'.", issue_id);
+    Ok(())
+}
+
+// Runs the test command in the candidate's worktree, recreating the worktree if the session
+// already cleaned it up. Returns whether it passed and the tail of its output.
+fn run_race_test(git_root: &Path, branch: &str, cmd: &str, verbose: bool) -> Result<(bool, String)> {
+    let worktree = issue_worktree_path(git_root, branch)?;
+    if !worktree.exists() {
+        if verbose {
+            println!("Recreating worktree at {}...", worktree.display());
+        }
+        let (main_repo_path, _) = get_git_common_dir(git_root)?;
+        let status = Command::new("git")
+            .arg("worktree")
+            .arg("add")
+            .arg(&worktree)
+            .arg(branch)
+            .current_dir(&main_repo_path)
+            .stdout(Stdio::null())
+            .status()
+            .context("Failed to execute git worktree add")?;
+        if !status.success() {
+            bail!("Failed to check out {} to run the tests", branch);
+        }
+        bootstrap_worktree(&worktree, verbose)?;
+    }
+
+    if verbose {
+        println!("Running '{}' in {}...", cmd, worktree.display());
+    }
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .current_dir(&worktree)
+        .output()
+        .with_context(|| format!("Failed to execute '{}'", cmd))?;
+
+    let mut text = String::from_utf8_lossy(&output.stdout).to_string();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    Ok((output.status.success(), last_lines(text.trim_end(), 10)))
+}
+
+fn race_ci_summary(cwd: &Path, branch: &str) -> String {
+    let output = Command::new("gh")
+        .arg("pr")
+        .arg("checks")
+        .arg(branch)
+        .arg("--json")
+        .arg("bucket")
+        .arg("--jq")
+        .arg(".[].bucket")
+        .current_dir(cwd)
+        .output();
+
+    let Ok(output) = output else {
+        return "unknown (failed to run gh)".to_string();
+    };
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let buckets: Vec<&str> = stdout.lines().collect();
+    if buckets.is_empty() {
+        return "no PR or no checks".to_string();
+    }
+
+    let count = |bucket: &str| buckets.iter().filter(|b| **b == bucket).count();
+    let (pass, fail, pending) = (count("pass"), count("fail"), count("pending"));
+    let summary = format!("{} passed, {} failed, {} pending", pass, fail, pending);
+    if fail > 0 {
+        summary.red().to_string()
+    } else if pending > 0 {
+        summary.yellow().to_string()
+    } else {
+        summary.green().to_string()
+    }
+}
+
+// Keeps one race candidate: its branch becomes the issue branch and gets the PR, the other
+// candidates' sessions, worktrees, branches and PRs are discarded.
+fn handle_pick(issue_id: String, agent: String, verbose: bool, backend: IssueBackend) -> Result<()> {
+    let current_dir = env::current_dir().context("Failed to get current directory")?;
+    let git_root = find_git_root(&current_dir).context("Not in a git repository")?;
+    let (main_repo_path, _) = get_git_common_dir(&git_root)?;
+
+    let candidates = race_candidates(&git_root, &issue_id)?;
+    if !candidates.contains(&agent) {
+        bail!("{} is not racing on {}. Candidates: {}", agent, issue_id, candidates.join(", "));
+    }
+
+    let winner = race_branch(&issue_id, &agent);
+    if session_host(&git_root, &winner).is_alive(&format!("fuzemill-{}", winner)) {
+        bail!("The {} session is still running. Wait for it to finish, or end it with 'fuzemill done'.", agent);
+    }
+    if list_local_branches(&main_repo_path)?.contains(&issue_id) {
+        bail!("Branch {} already exists. Remove it first (e.g. with 'fuzemill unstart {}').", issue_id, issue_id);
+    }
+
+    for candidate in &candidates {
+        let branch = race_branch(&issue_id, candidate);
+        let worktree = issue_worktree_path(&git_root, &branch)?;
+
+        // Remove the hook first, so ending the session does not open a PR
+        remove_cleanup_hook(&branch, &worktree, verbose);
+        let session_name = format!("fuzemill-{}", branch);
+        let host = session_host(&git_root, &branch);
+        if host.is_alive(&session_name) {
+            println!("Ending {} session...", candidate);
+            let _ = host.kill(&session_name);
+        }
+
+        close_race_pr(&main_repo_path, &branch, &issue_id, &agent, verbose);
+
+        if branch == winner {
+            continue;
+        }
+
+        if worktree.exists() {
+            if verbose {
+                println!("Removing worktree {}...", worktree.display());
+            }
+            let status = Command::new("git")
+                .arg("worktree")
+                .arg("remove")
+                .arg("--force")
+                .arg(&worktree)
+                .current_dir(&main_repo_path)
+                .status()
+                .context("Failed to execute git worktree remove")?;
+            if !status.success() {
+                eprintln!("Warning: Failed to remove worktree at {}", worktree.display());
+            }
+        }
+
+        let output = Command::new("git")
+            .arg("branch")
+            .arg("-D")
+            .arg(&branch)
+            .current_dir(&main_repo_path)
+            .output()
+            .context("Failed to delete branch")?;
+        if !output.status.success() {
+            eprintln!("Warning: Failed to delete branch {}", branch);
+        }
+        set_issue_outcome(&git_root, &branch, "discarded");
+        println!("Discarded {} ({})", candidate, branch);
+    }
+
+    // Promote the winner to the issue branch
+    let status = Command::new("git")
+        .arg("branch")
+        .arg("-m")
+        .arg(&winner)
+        .arg(&issue_id)
+        .current_dir(&main_repo_path)
+        .status()
+        .context("Failed to execute 'git branch -m'")?;
+    if !status.success() {
+        bail!("Failed to rename branch {} to {}", winner, issue_id);
+    }
+
+    let old_worktree = issue_worktree_path(&git_root, &winner)?;
+    let worktree = issue_worktree_path(&git_root, &issue_id)?;
+    let kept_worktree = old_worktree.exists();
+    if kept_worktree {
+        let status = Command::new("git")
+            .arg("worktree")
+            .arg("move")
+            .arg(&old_worktree)
+            .arg(&worktree)
+            .current_dir(&main_repo_path)
+            .status()
+            .context("Failed to execute git worktree move")?;
+        if !status.success() {
+            bail!("Failed to move worktree {} to {}", old_worktree.display(), worktree.display());
+        }
+    } else {
+        let status = Command::new("git")
+            .arg("worktree")
+            .arg("add")
+            .arg(&worktree)
+            .arg(&issue_id)
+            .current_dir(&main_repo_path)
+            .stdout(Stdio::null())
+            .status()
+            .context("Failed to execute git worktree add")?;
+        if !status.success() {
+            bail!("Failed to check out {} at {}", issue_id, worktree.display());
+        }
+    }
+
+    // The winner's runs become the issue's runs, so 'logs' and the PR body pick them up
+    let old_runs = issue_state_dir(&git_root, &winner)?.join("runs");
+    let runs_dir = issue_state_dir(&git_root, &issue_id)?.join("runs");
+    fs::create_dir_all(&runs_dir).with_context(|| format!("Failed to create {}", runs_dir.display()))?;
+    for run_dir in list_runs(&git_root, &winner)? {
+        let target = runs_dir.join(run_dir.file_name().context("Invalid run directory")?);
+        fs::rename(&run_dir, &target).with_context(|| format!("Failed to move {}", run_dir.display()))?;
+    }
+    let _ = fs::remove_dir(&old_runs);
+
+    let mut record = IssueRecord::load(&git_root, &issue_id)?;
+    record.candidates.clear();
+    record.save(&git_root, &issue_id)?;
+
+    println!("{}", format!("Picked {}: {} is now {}.", agent, winner, issue_id).green());
+
+    match ensure_pr(&worktree, verbose, backend) {
+        Ok(Some(url)) => println!("PR: {}", url),
+        Ok(None) => {}
+        Err(e) => eprintln!("Warning: Failed to open PR for {}: {}", issue_id, e),
+    }
+
+    // A worktree that existed before had leftovers worth keeping; the recreated one is not needed
+    if kept_worktree {
+        println!("Worktree: {}", worktree.display());
+        Ok(())
+    } else {
+        cleanup_worktree(&issue_id, &worktree, verbose)
+    }
+}
+
+// Closes a candidate's PR, if it has one, and deletes its remote branch
+fn close_race_pr(cwd: &Path, branch: &str, issue_id: &str, winner: &str, verbose: bool) {
+    if let Ok(pr) = fetch_pull_request(cwd, branch) {
+        if verbose {
+            println!("Closing {}...", pr.url);
+        }
+        let output = Command::new("gh")
+            .arg("pr")
+            .arg("close")
+            .arg(pr.number.to_string())
+            .arg("--comment")
+            .arg(format!("Closed by fuzemill: {} was picked for {}.", winner, issue_id))
+            .current_dir(cwd)
+            .output();
+        if !output.is_ok_and(|o| o.status.success()) {
+            eprintln!("Warning: Failed to close {}", pr.url);
+        }
+    }
+
+    // Not every candidate got pushed, so a failure here is expected
+    let _ = Command::new("git")
+        .arg("push")
+        .arg("origin")
+        .arg("--delete")
+        .arg(branch)
+        .current_dir(cwd)
+        .output();
+}
+
+// An issue that is ready for an agent, see fetch_ready_issues
+struct ReadyIssue {
+    id: String,
+    title: String,
+    // Lower comes first
+    priority: usize,
+}
+
+fn handle_next(list: bool, verbose: bool, backend: IssueBackend) -> Result<()> {
+    let current_dir = env::current_dir().context("Failed to get current directory")?;
+    let git_root = find_git_root(&current_dir).context("Not in a git repository")?;
+    let config = load_config(&git_root)?.queue;
+    let ready = fetch_ready_issues(&git_root, &config, verbose, backend)?;
+
+    if list {
+        if ready.is_empty() {
+            println!("No ready issues.");
+            return Ok(());
+        }
+        println!("{:<12} {:<9} TITLE", "ISSUE", "PRIORITY");
+        for issue in &ready {
+            println!("{:<12} {:<9} {}", issue.id, issue.priority, issue.title);
+        }
+        return Ok(());
+    }
+
+    let Some(issue) = ready.into_iter().next() else {
+        println!("No ready issues.");
+        return Ok(());
+    };
+    println!("Next ready issue: {} ({})", issue.id, issue.title);
+    handle_start(Some(issue.id), None, vec![config.agent], None, Vec::new(), verbose, backend)
+}
+
+// Starts ready issues in priority order while fewer than the configured number of agents are
+// running. Issues that a merge unblocks are picked up on the next poll.
+fn handle_run_queue(interval: u64, once: bool, concurrency: Option<usize>, verbose: bool, backend: IssueBackend) -> Result<()> {
+    let current_dir = env::current_dir().context("Failed to get current directory")?;
+    let git_root = find_git_root(&current_dir).context("Not in a git repository")?;
+    let config = load_config(&git_root)?;
+    let runner = config.resolve_runner()?;
+    if runner == Runner::Foreground {
+        bail!("The queue runs agents side by side, which needs the tmux, zellij, screen or headless runner.");
+    }
+    let concurrency = concurrency.unwrap_or(config.queue.concurrency);
+    preflight(&git_root, &config.queue.agent, verbose, backend)?;
+
+    if !once {
+        println!("Running the queue with up to {} agents, checking every {}s (Ctrl-C to stop)...", concurrency, interval);
+    }
+    // Each issue is started once per queue run, so a failing one isn't retried forever
+    let mut started: Vec<String> = Vec::new();
+    let mut children: Vec<(String, Child)> = Vec::new();
+
+    loop {
+        children.retain_mut(|(id, child)| match child.try_wait() {
+            Ok(None) => true,
+            Ok(Some(status)) => {
+                let result = if status.success() { "finished".to_string() } else { format!("failed ({})", status) };
+                println!("Headless run for {} {}.", id, result);
+                false
+            }
+            Err(_) => false,
+        });
+        let running = match runner.host() {
+            Some(host) => host.list().iter().filter(|s| s.starts_with("fuzemill-")).count(),
+            None => children.len(),
+        };
+
+        let free = concurrency.saturating_sub(running);
+        if free > 0 {
+            match fetch_ready_issues(&git_root, &config.queue, verbose, backend) {
+                Ok(mut ready) => {
+                    ready.retain(|i| !started.contains(&i.id));
+                    for issue in ready.into_iter().take(free) {
+                        started.push(issue.id.clone());
+                        println!("{}", format!("Starting {} ({})...", issue.id, issue.title).green());
+                        match start_queued_issue(&git_root, &issue.id, &config.queue.agent, runner, verbose, backend) {
+                            Ok(Some(child)) => children.push((issue.id, child)),
+                            Ok(None) => {}
+                            Err(e) => eprintln!("Warning: Failed to start {}: {}", issue.id, e),
+                        }
+                    }
+                }
+                Err(e) => eprintln!("Warning: Failed to fetch ready issues: {}", e),
+            }
+        } else if verbose {
+            println!("{} agents running, no free slot.", running);
+        }
+
+        if once {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_secs(interval));
+    }
+}
+
+// Starts an issue without attaching to it: in a detached session, or for the headless runner
+// as a 'fuzemill start' child process logging to queue.log in the issue's state directory
+fn start_queued_issue(git_root: &Path, issue_id: &str, agent: &str, runner: Runner, verbose: bool, backend: IssueBackend) -> Result<Option<Child>> {
+    let worktree = issue_worktree_path(git_root, issue_id)?;
+    let Some(host) = runner.host() else {
+        let state_dir = issue_state_dir(git_root, issue_id)?;
+        fs::create_dir_all(&state_dir).with_context(|| format!("Failed to create {}", state_dir.display()))?;
+        let log_path = state_dir.join("queue.log");
+        let log = fs::File::create(&log_path).with_context(|| format!("Failed to create {}", log_path.display()))?;
+
+        let mut command = Command::new(env::current_exe().context("Failed to find the fuzemill executable")?);
+        if backend == IssueBackend::Beads {
+            command.arg("--use-bd");
+        }
+        if verbose {
+            command.arg("--verbose");
+        }
+        let child = command
+            .arg("start")
+            .arg("--id")
+            .arg(issue_id)
+            .arg("--agent")
+            .arg(agent)
+            .env("FUZEMILL_RUNNER", "headless")
+            .current_dir(git_root)
+            .stdin(Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log)
+            .spawn()
+            .context("Failed to start 'fuzemill start'")?;
+        println!("Running {} headless (output in {})", issue_id, log_path.display());
+        return Ok(Some(child));
+    };
+
+    run_hook(git_root, "pre-start", issue_id, issue_id, &worktree, verbose)?;
+    create_worktree(issue_id, &worktree, None, verbose)?;
+    bootstrap_worktree(&worktree, verbose)?;
+    run_hook(git_root, "post-worktree", issue_id, issue_id, &worktree, verbose)?;
+    if let Err(e) = update_issue_status(git_root, issue_id, "hooked", verbose, backend) {
+        eprintln!("Warning: Failed to set issue status to 'hooked': {}", e);
+    }
+
+    let config = load_config(git_root)?;
+    let session = AgentSession {
+        prompt: agent_prompt(issue_id, agent_co_author(agent)?, config.pr.create, backend),
+        issue_id: issue_id.to_string(),
+        branch: issue_id.to_string(),
+        worktree,
+        agent: agent.to_string(),
+        model: None,
+        launch_hook: Some("post-start"),
+    };
+    let session_name = format!("fuzemill-{}", issue_id);
+    launch_agent_session(git_root, runner, host, &session, &session_name, verbose, backend)?;
+
+    if let Err(e) = update_issue_status(git_root, issue_id, "in_progress", verbose, backend) {
+        eprintln!("Warning: Failed to set issue status to 'in_progress': {}", e);
+    }
+    println!("Watch it with: {}", host.attach_command(&session_name));
+    Ok(None)
+}
+
+// Open, unassigned issues with the queue label and no open blockers, highest priority first.
+// Issues fuzemill already has a branch, worktree or session for are left out.
+fn fetch_ready_issues(git_root: &Path, config: &QueueConfig, verbose: bool, backend: IssueBackend) -> Result<Vec<ReadyIssue>> {
+    let mut ready = match backend {
+        IssueBackend::Beads => fetch_ready_beads(git_root, config)?,
+        IssueBackend::GitHub => fetch_ready_github(git_root, config, verbose)?,
+    };
+
+    let sessions: Vec<String> = SessionHost::MULTIPLEXERS.iter().flat_map(|h| h.list()).collect();
+    ready.retain(|issue| {
+        let worktree_exists = issue_worktree_path(git_root, &issue.id).is_ok_and(|p| p.exists());
+        let branch_exists = find_branch_ref(git_root, &[format!("refs/heads/{}", issue.id)]).is_some();
+        if verbose && (worktree_exists || branch_exists) {
+            println!("{} was already started.", issue.id);
+        }
+        !worktree_exists && !branch_exists && !sessions.contains(&format!("fuzemill-{}", issue.id))
+    });
+    // Stable, so equal priorities keep the backend's order
+    ready.sort_by_key(|issue| issue.priority);
+    Ok(ready)
+}
+
+// 'bd ready' already leaves out issues with open blockers
+fn fetch_ready_beads(git_root: &Path, config: &QueueConfig) -> Result<Vec<ReadyIssue>> {
+    let output = Command::new("bd")
+        .arg("ready")
+        .arg("--json")
+        .current_dir(git_root)
+        .output()
+        .context("Failed to execute 'bd ready'")?;
+    if !output.status.success() {
+        bail!("bd ready failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    let issues: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout).context("Failed to parse 'bd ready' output")?;
+    Ok(issues
+        .iter()
+        .filter(|issue| issue.get("assignee").and_then(|a| a.as_str()).unwrap_or("").is_empty())
+        .filter(|issue| {
+            let labels = issue.get("labels").and_then(|l| l.as_array());
+            config.label.is_empty() || labels.is_some_and(|l| l.iter().any(|l| l.as_str() == Some(&config.label)))
+        })
+        .filter_map(|issue| {
+            Some(ReadyIssue {
+                id: issue.get("id")?.as_str()?.to_string(),
+                title: issue.get("title").and_then(|t| t.as_str()).unwrap_or("").to_string(),
+                priority: issue.get("priority").and_then(|p| p.as_u64()).unwrap_or(2) as usize,
+            })
+        })
+        .collect())
+}
+
+// An issue is blocked by its open sub-issues, by open issues it is marked as blocked by, and
+// by open issues in unchecked items of its task list ("- [ ] #12")
+fn fetch_ready_github(git_root: &Path, config: &QueueConfig, verbose: bool) -> Result<Vec<ReadyIssue>> {
+    let mut command = Command::new("gh");
+    command
+        .arg("issue")
+        .arg("list")
+        .arg("--state")
+        .arg("open")
+        .arg("--search")
+        .arg("no:assignee")
+        .arg("--limit")
+        .arg("200")
+        .arg("--json")
+        .arg("number,title,body,labels");
+    if !config.label.is_empty() {
+        command.arg("--label").arg(&config.label);
+    }
+    let output = command.current_dir(git_root).output().context("Failed to execute 'gh issue list'")?;
+    if !output.status.success() {
+        bail!("gh issue list failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    let issues: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout).context("Failed to parse 'gh issue list' output")?;
+
+    let output = Command::new("gh")
+        .arg("issue")
+        .arg("list")
+        .arg("--state")
+        .arg("open")
+        .arg("--limit")
+        .arg("1000")
+        .arg("--json")
+        .arg("number")
+        .arg("--jq")
+        .arg(".[].number")
+        .current_dir(git_root)
+        .output()
+        .context("Failed to execute 'gh issue list'")?;
+    let open: Vec<u64> = String::from_utf8_lossy(&output.stdout).lines().filter_map(|l| l.trim().parse().ok()).collect();
+
+    let mut ready = Vec::new();
+    for issue in &issues {
+        let Some(number) = issue.get("number").and_then(|n| n.as_u64()) else {
+            continue;
+        };
+        let labels: Vec<&str> = issue
+            .get("labels")
+            .and_then(|l| l.as_array())
+            .map(|l| l.iter().filter_map(|l| l.get("name")?.as_str()).collect())
+            .unwrap_or_default();
+        // fuzemill's status labels mean it was already started
+        if labels.iter().any(|l| l.starts_with("status:")) {
+            continue;
+        }
+
+        let body = issue.get("body").and_then(|b| b.as_str()).unwrap_or("");
+        let task_blocker = task_list_references(body).into_iter().find(|n| *n != number && open.contains(n));
+        let blocker = match task_blocker {
+            Some(n) => Some(format!("#{} in its task list", n)),
+            None => github_issue_blocker(git_root, number),
+        };
+        if let Some(blocker) = blocker {
+            if verbose {
+                println!("#{} is blocked by {}.", number, blocker);
+            }
+            continue;
+        }
+
+        let priority = config
+            .priority_labels
+            .iter()
+            .position(|p| labels.contains(&p.as_str()))
+            .unwrap_or(config.priority_labels.len());
+        ready.push(ReadyIssue {
+            id: number.to_string(),
+            title: issue.get("title").and_then(|t| t.as_str()).unwrap_or("").to_string(),
+            priority,
+        });
+    }
+    // Oldest first within a priority
+    ready.sort_by_key(|issue| issue.id.parse::<u64>().unwrap_or(u64::MAX));
+    Ok(ready)
+}
+
+// Issue numbers referenced in unchecked task list items
+fn task_list_references(body: &str) -> Vec<u64> {
+    body.lines()
+        .filter_map(|l| l.trim_start().strip_prefix("- [ ]").or_else(|| l.trim_start().strip_prefix("* [ ]")))
+        .flat_map(|item| {
+            item.split(|c: char| !c.is_ascii_alphanumeric() && c != '#')
+                .filter_map(|word| word.strip_prefix('#')?.parse().ok())
+                .collect::<Vec<u64>>()
+        })
+        .collect()
+}
+
+// Open sub-issues or "blocked by" dependencies; errors (e.g. on servers without these APIs)
+// count as not blocked
+fn github_issue_blocker(git_root: &Path, number: u64) -> Option<String> {
+    let gh_api = |path: String, jq: &str| {
+        Command::new("gh")
+            .arg("api")
+            .arg(path)
+            .arg("--jq")
+            .arg(jq)
+            .current_dir(git_root)
+            .output()
+            .ok()
+            .filter(|o| o.status.success())
+            .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+    };
+
+    let open_sub_issues = gh_api(
+        format!("repos/{{owner}}/{{repo}}/issues/{}", number),
+        "(.sub_issues_summary.total // 0) - (.sub_issues_summary.completed // 0)",
+    );
+    if let Some(count) = open_sub_issues.and_then(|c| c.parse::<u64>().ok()).filter(|c| *c > 0) {
+        return Some(format!("{} open sub-issue(s)", count));
+    }
+
+    let blocked_by = gh_api(
+        format!("repos/{{owner}}/{{repo}}/issues/{}/dependencies/blocked_by", number),
+        "[.[] | select(.state == \"open\") | \"#\\(.number)\"] | join(\", \")",
+    );
+    blocked_by.filter(|b| !b.is_empty())
+}
+
+// What the watch loop last saw of an issue branch
+#[derive(Default)]
+struct WatchedBranch {
+    session_alive: bool,
+    pr_url: Option<String>,
+    ci: Option<&'static str>,
+    last_activity: Option<u64>,
+    idle_notified: bool,
+    limit_hit: bool,
+}
+
+// Polls the state store, tmux and GitHub, and sends a notification whenever a session ends,
+// a PR is opened, CI finishes, or an agent has been idle for a while. The first poll only
+// records the current state.
+fn handle_watch(interval: u64, verbose: bool, backend: IssueBackend) -> Result<()> {
+    let current_dir = env::current_dir().context("Failed to get current directory")?;
+    let git_root = find_git_root(&current_dir).context("Not in a git repository")?;
+    let Config { notify: config, limits, .. } = load_config(&git_root)?;
+
+    println!("Watching fuzemill sessions every {}s (Ctrl-C to stop)...", interval);
+    let mut watched: Vec<(String, WatchedBranch)> = Vec::new();
+    let mut first = true;
+
+    loop {
+        let branches = list_state_branches(&git_root)?;
+        let activity = list_session_activity();
+        // Only tmux reports when a session last had output
+        let other_sessions: Vec<String> = [SessionHost::Zellij, SessionHost::Screen].iter().flat_map(|h| h.list()).collect();
+        let ci = fetch_open_pr_checks(&git_root, verbose);
+        let now = unix_now();
+
+        for branch in branches {
+            let index = match watched.iter().position(|(b, _)| *b == branch) {
+                Some(i) => i,
+                None => {
+                    watched.push((branch.clone(), WatchedBranch::default()));
+                    watched.len() - 1
+                }
+            };
+            let state = &mut watched[index].1;
+            let session_name = format!("fuzemill-{}", branch);
+            let mut events = Vec::new();
+
+            let current_activity = activity.iter().find(|(name, _)| *name == session_name).map(|(_, a)| *a);
+            let alive = current_activity.is_some() || other_sessions.contains(&session_name);
+            if state.session_alive && !alive {
+                let exit_code = match list_runs(&git_root, &branch) {
+                    Ok(runs) => runs.last().and_then(|r| read_agent_exit_code(r)),
+                    Err(e) => {
+                        eprintln!("Warning: Failed to read the runs of {}: {}", branch, e);
+                        None
+                    }
+                };
+                let message = match exit_code {
+                    Some(code) if code != 0 => format!("The agent session for {} failed (exit code {})", branch, code),
+                    _ => format!("The agent session for {} ended", branch),
+                };
+                events.push(("session-ended", message));
+            }
+
+            if alive {
+                let mut idle_secs = 0;
+                if let Some(activity) = current_activity {
+                    if state.last_activity != Some(activity) {
+                        state.last_activity = Some(activity);
+                        state.idle_notified = false;
+                    }
+                    idle_secs = now.saturating_sub(activity);
+                    if config.idle_minutes > 0 && idle_secs >= config.idle_minutes * 60 && !state.idle_notified {
+                        state.idle_notified = true;
+                        events.push(("idle", format!("The agent for {} has produced no output for {} minutes", branch, idle_secs / 60)));
+                    }
+                }
+
+                // Each session is acted on once; a new session starts with a clean slate
+                if !state.session_alive {
+                    state.limit_hit = false;
+                }
+                let exceeded = if state.limit_hit {
+                    None
+                } else {
+                    exceeded_limit(&git_root, &branch, &limits, idle_secs, now).unwrap_or_else(|e| {
+                        eprintln!("Warning: Failed to check the limits of {}: {}", branch, e);
+                        None
+                    })
+                };
+                if let Some(reason) = exceeded {
+                    state.limit_hit = true;
+                    let action = match limits.policy {
+                        LimitPolicy::Interrupt => "interrupting it",
+                        LimitPolicy::Salvage => "committing its work and ending the session",
+                        LimitPolicy::End => "ending the session",
+                    };
+                    notify(&config, "limit", &branch, &format!("The agent for {} {}; {}", branch, reason, action), verbose);
+                    if let Err(e) = enforce_limit(&git_root, &branch, limits.policy, &reason, verbose, backend) {
+                        eprintln!("Warning: Failed to enforce the limit on {}: {}", branch, e);
+                    }
+                }
+            }
+            state.session_alive = alive;
+
+            let pr_url = match IssueRecord::load(&git_root, &branch) {
+                Ok(record) => record.pr_url,
+                Err(e) => {
+                    eprintln!("Warning: Failed to read the issue record of {}: {}", branch, e);
+                    state.pr_url.clone()
+                }
+            };
+            if pr_url.is_some() && pr_url != state.pr_url {
+                events.push(("pr-opened", format!("Opened PR for {}: {}", branch, pr_url.as_deref().unwrap_or(""))));
+            }
+            state.pr_url = pr_url;
+
+            let bucket = ci.iter().find(|(b, _)| *b == branch).map(|(_, bucket)| *bucket);
+            if bucket != state.ci {
+                match bucket {
+                    Some("pass") => events.push(("ci-passed", format!("CI passed for {}", branch))),
+                    Some("fail") => events.push(("ci-failed", format!("CI failed for {}", branch))),
+                    _ => {}
+                }
+                state.ci = bucket;
+            }
+
+            if !first {
+                for (event, message) in events {
+                    notify(&config, event, &branch, &message, verbose);
+                }
+            }
+        }
+
+        first = false;
+        thread::sleep(Duration::from_secs(interval));
+    }
+}
+
+// Returns the first limit the session has exceeded, if any
+fn exceeded_limit(git_root: &Path, branch: &str, limits: &LimitsConfig, idle_secs: u64, now: u64) -> Result<Option<String>> {
+    let runs = list_runs(git_root, branch)?;
+    let Some(run) = runs.last().and_then(|r| RunRecord::load(r).ok()) else {
+        return Ok(None);
+    };
+
+    if let Some(minutes) = limits.wall_clock_minutes
+        && let Some(started) = parse_utc_timestamp(&run.started_at)
+        && now.saturating_sub(started) >= minutes * 60
+    {
+        return Ok(Some(format!("ran for more than {} minutes", minutes)));
+    }
+
+    if let Some(minutes) = limits.idle_minutes
+        && idle_secs >= minutes * 60
+    {
+        return Ok(Some(format!("produced no output for {} minutes", minutes)));
+    }
+
+    if let Some(max) = limits.max_commits
+        && let Some(base) = &run.base_commit
+    {
+        let output = Command::new("git")
+            .arg("rev-list")
+            .arg("--count")
+            .arg(format!("{}..{}", base, branch))
+            .current_dir(git_root)
+            .output()
+            .context("Failed to execute 'git rev-list'")?;
+        let commits: u32 = String::from_utf8_lossy(&output.stdout).trim().parse().unwrap_or(0);
+        if commits > max {
+            return Ok(Some(format!("made {} commits (limit {})", commits, max)));
+        }
+    }
+
+    Ok(None)
+}
+
+// Applies the limit policy to a session that exceeded a limit
+fn enforce_limit(git_root: &Path, branch: &str, policy: LimitPolicy, reason: &str, verbose: bool, backend: IssueBackend) -> Result<()> {
+    let session_name = format!("fuzemill-{}", branch);
+    let worktree_path = issue_worktree_path(git_root, branch)?;
+    let last_run = list_runs(git_root, branch)?.pop();
+
+    if let Some(run_dir) = &last_run
+        && let Ok(mut run) = RunRecord::load(run_dir)
+    {
+        run.limit = Some(reason.to_string());
+        run.save(run_dir)?;
+    }
+
+    if policy == LimitPolicy::Interrupt {
+        if verbose {
+            println!("Interrupting the agent in '{}'...", session_name);
+        }
+        return session_host(git_root, branch).send_keys(&session_name, Keys::Interrupt);
+    }
+
+    if policy == LimitPolicy::Salvage && worktree_path.exists() && worktree_has_changes(&worktree_path)? {
+        if verbose {
+            println!("Committing the uncommitted work in {}...", worktree_path.display());
+        }
+        let status = worktree_git(&worktree_path)?
+            .arg("add")
+            .arg("-A")
+            .status()
+            .context("Failed to execute 'git add'")?;
+        if !status.success() {
+            bail!("git add failed in {}", worktree_path.display());
+        }
+        // The worktree's commit-msg hook adds the trailers
+        let status = worktree_git(&worktree_path)?
+            .arg("commit")
+            .arg("-m")
+            .arg(format!("WIP: salvaged by fuzemill after the agent {}", reason))
+            .status()
+            .context("Failed to execute 'git commit'")?;
+        if !status.success() {
+            bail!("Failed to commit the salvaged work in {}", worktree_path.display());
+        }
+    }
+
+    // Without the hook, ending the session leaves the worktree in place and opens no PR
+    remove_cleanup_hook(branch, &worktree_path, verbose);
+    if let Some(run_dir) = &last_run
+        && let Ok(mut run) = RunRecord::load(run_dir)
+    {
+        run.finish(&worktree_path, None);
+        run.save(run_dir)?;
+    }
+    let _ = session_host(git_root, branch).kill(&session_name);
+
+    let issue_id = last_run
+        .as_deref()
+        .and_then(|d| RunRecord::load(d).ok())
+        .map(|r| r.issue_id)
+        .unwrap_or_else(|| branch.to_string());
+    update_issue_status(git_root, &issue_id, "blocked", verbose, backend)
+}
+
+fn handle_say(issue_id: String, message: Option<String>, file: Option<PathBuf>, verbose: bool) -> Result<()> {
+    let current_dir = env::current_dir().context("Failed to get current directory")?;
+    let git_root = find_git_root(&current_dir).context("Not in a git repository")?;
+
+    let message = match file {
+        Some(path) if path == Path::new("-") => {
+            let mut text = String::new();
+            io::stdin().read_to_string(&mut text).context("Failed to read the message from stdin")?;
+            text
+        }
+        Some(path) => fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?,
+        None => message.unwrap_or_default(),
+    };
+    let message = message.trim();
+    if message.is_empty() {
+        bail!("The message is empty.");
+    }
+
+    // A race has a session per candidate; the message goes to each one that is running
+    let mut branches = vec![issue_id.clone()];
+    branches.extend(IssueRecord::load(&git_root, &issue_id)?.candidates.iter().map(|agent| race_branch(&issue_id, agent)));
+
+    let mut sent = 0;
+    for branch in branches {
+        let session_name = format!("fuzemill-{}", branch);
+        let host = session_host(&git_root, &branch);
+        if !host.is_alive(&session_name) {
+            continue;
+        }
+        let Some(run_dir) = list_runs(&git_root, &branch)?.pop() else {
+            continue;
+        };
+        let agent = RunRecord::load(&run_dir)?.agent;
+
+        if verbose {
+            println!("Sending the message to {} in '{}'...", agent, session_name);
+        }
+        host.send_keys(&session_name, Keys::Paste(message))?;
+        thread::sleep(paste_settle_time(&agent));
+        host.send_keys(&session_name, Keys::Enter)?;
+
+        // The transcript only shows what the agent echoes, so mark the message itself
+        let transcript_path = run_dir.join("transcript.log");
+        let entry = format!("\r\n--- fuzemill say ({}) ---\r\n{}\r\n---\r\n", utc_timestamp(unix_now()), message.replace('\n', "\r\n"));
+        let logged = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&transcript_path)
+            .and_then(|mut f| f.write_all(entry.as_bytes()));
+        if let Err(e) = logged {
+            eprintln!("Warning: Failed to record the message in {}: {}", transcript_path.display(), e);
+        }
+
+        println!("Sent to {} ({}).", session_name, agent);
+        sent += 1;
+    }
+
+    if sent == 0 {
+        bail!("No running session for {}.", issue_id);
+    }
+    Ok(())
+}
+
+// A keypress that arrives in the same burst as a paste is read as part of it, which makes
+// Enter a newline in the input instead of submitting it. Claude takes longer to settle.
+fn paste_settle_time(agent: &str) -> Duration {
+    match agent {
+        "claude" => Duration::from_millis(500),
+        _ => Duration::from_millis(200),
+    }
+}
+
+// Branches with state in the store, i.e. everything fuzemill has run an agent on
+fn list_state_branches(git_root: &Path) -> Result<Vec<String>> {
+    let issues_dir = state_dir(git_root)?.join("issues");
+    if !issues_dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut branches: Vec<String> = fs::read_dir(&issues_dir)
+        .with_context(|| format!("Failed to read {}", issues_dir.display()))?
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().is_dir())
+        .filter_map(|e| e.file_name().to_str().map(String::from))
+        .collect();
+    branches.sort();
+    Ok(branches)
+}
+
+// Last output time (unix seconds) of every tmux session. pane_activity needs tmux 3.4;
+// older versions only have window_activity, which is the same for single-pane sessions.
+fn list_session_activity() -> Vec<(String, u64)> {
+    let output = Command::new("tmux")
+        .arg("list-panes")
+        .arg("-a")
+        .arg("-F")
+        .arg("#{session_name}\t#{pane_activity}\t#{window_activity}")
+        .output();
+
+    let Ok(output) = output else {
+        return Vec::new();
+    };
+    let mut sessions: Vec<(String, u64)> = Vec::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let mut fields = line.split('\t');
+        let (Some(name), Some(pane), Some(window)) = (fields.next(), fields.next(), fields.next()) else {
+            continue;
+        };
+        let Ok(activity) = (if pane.is_empty() { window } else { pane }).parse::<u64>() else {
+            continue;
+        };
+        match sessions.iter_mut().find(|(n, _)| n == name) {
+            Some(entry) => entry.1 = entry.1.max(activity),
+            None => sessions.push((name.to_string(), activity)),
+        }
+    }
+    sessions
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PrChecks {
+    head_ref_name: String,
+    status_check_rollup: Vec<CheckRollupItem>,
+}
+
+// A check run (status/conclusion) or a commit status (state)
+#[derive(Deserialize)]
+struct CheckRollupItem {
+    status: Option<String>,
+    conclusion: Option<String>,
+    state: Option<String>,
+}
+
+// The CI state ("pass", "fail" or "pending") of every open PR, by branch, in one gh call
+fn fetch_open_pr_checks(cwd: &Path, verbose: bool) -> Vec<(String, &'static str)> {
+    let output = Command::new("gh")
+        .arg("pr")
+        .arg("list")
+        .arg("--state")
+        .arg("open")
+        .arg("--json")
+        .arg("headRefName,statusCheckRollup")
+        .current_dir(cwd)
+        .output();
+
+    let prs: Vec<PrChecks> = match output {
+        Ok(output) if output.status.success() => match serde_json::from_slice(&output.stdout) {
+            Ok(prs) => prs,
+            Err(e) => {
+                eprintln!("Warning: Failed to parse the open PRs from gh: {}", e);
+                return Vec::new();
+            }
+        },
+        _ => {
+            if verbose {
+                eprintln!("Warning: Failed to list open PRs with gh");
+            }
+            return Vec::new();
+        }
+    };
+
+    prs.into_iter()
+        .filter(|pr| !pr.status_check_rollup.is_empty())
+        .map(|pr| {
+            let results: Vec<&str> = pr
+                .status_check_rollup
+                .iter()
+                .map(|c| match (c.status.as_deref(), c.conclusion.as_deref(), c.state.as_deref()) {
+                    (_, Some("FAILURE" | "CANCELLED" | "TIMED_OUT" | "ACTION_REQUIRED" | "STARTUP_FAILURE"), _) => "fail",
+                    (_, _, Some("FAILURE" | "ERROR")) => "fail",
+                    (Some("COMPLETED"), _, _) | (_, _, Some("SUCCESS")) => "pass",
+                    _ => "pending",
+                })
+                .collect();
+            let bucket = if results.contains(&"fail") {
+                "fail"
+            } else if results.contains(&"pending") {
+                "pending"
+            } else {
+                "pass"
+            };
+            (pr.head_ref_name, bucket)
+        })
+        .collect()
+}
+
+// Sends a notification to every configured target
+fn notify(config: &NotifyConfig, event: &str, branch: &str, message: &str, verbose: bool) {
+    if !config.events.is_empty() && !config.events.iter().any(|e| e == event) {
+        return;
+    }
+
+    println!("[{}] {}", utc_timestamp(unix_now()), message);
+
+    if config.bell {
+        // BEL, plus OSC 9 for terminals that turn it into a desktop notification
+        print!("\x07\x1b]9;fuzemill: {}\x07", message);
+        let _ = io::stdout().flush();
+    }
+
+    if config.desktop {
+        let result = Command::new("notify-send")
+            .arg("--app-name=fuzemill")
+            .arg("fuzemill")
+            .arg(message)
+            .output();
+        if !result.is_ok_and(|o| o.status.success()) {
+            eprintln!("Warning: Failed to run notify-send");
+        }
+    }
+
+    if let Some(url) = &config.webhook {
+        let payload = serde_json::json!({
+            "event": event,
+            "branch": branch,
+            "message": message,
+            "text": format!("fuzemill: {}", message),
+        });
+        let mut command = Command::new("curl");
+        command
+            .arg("-fsS")
+            .arg("-X")
+            .arg("POST")
+            .arg("-H")
+            .arg("Content-Type: application/json")
+            .arg("--data-binary")
+            .arg("@-")
+            .arg(url);
+        match output_with_stdin(command, &payload.to_string()) {
+            Ok(output) if output.status.success() => {
+                if verbose {
+                    println!("Posted to webhook {}", url);
+                }
+            }
+            Ok(output) => eprintln!("Warning: Webhook failed: {}", String::from_utf8_lossy(&output.stderr).trim()),
+            Err(e) => eprintln!("Warning: Failed to execute 'curl': {}", e),
+        }
+    }
+}
+
+// Token usage of a run (or the sum of several), with the estimated cost
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct Usage {
+    input_tokens: u64,
+    output_tokens: u64,
+    cache_read_tokens: u64,
+    cache_write_tokens: u64,
+    cost_usd: f64,
+    // Models that answered, as named in the agent's logs
+    models: Vec<String>,
+}
+
+impl Usage {
+    fn add(&mut self, other: &Usage) {
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        self.cache_read_tokens += other.cache_read_tokens;
+        self.cache_write_tokens += other.cache_write_tokens;
+        self.cost_usd += other.cost_usd;
+        for model in &other.models {
+            if !self.models.contains(model) {
+                self.models.push(model.clone());
+            }
+        }
+    }
+}
+
+// List prices in USD per million tokens, matched against the model name. Used when the
+// agent does not report its cost; override or extend them with [[pricing]] in the config.
+const DEFAULT_PRICING: &[(&str, f64, f64, f64, f64)] = &[
+    // (model substring, input, output, cache read, cache write)
+    ("opus", 15.0, 75.0, 1.5, 18.75),
+    ("sonnet", 3.0, 15.0, 0.3, 3.75),
+    ("haiku", 0.8, 4.0, 0.08, 1.0),
+    ("gemini-2.5-pro", 1.25, 10.0, 0.31, 0.0),
+    ("gemini-2.5-flash", 0.3, 2.5, 0.075, 0.0),
+];
+
+fn estimate_cost(pricing: &[PriceConfig], model: &str, usage: &Usage) -> f64 {
+    let prices = pricing
+        .iter()
+        .find(|p| model.contains(&p.model))
+        .map(|p| (p.input, p.output, p.cache_read, p.cache_write))
+        .or_else(|| {
+            DEFAULT_PRICING
+                .iter()
+                .find(|(m, ..)| model.contains(m))
+                .map(|&(_, i, o, r, w)| (i, o, r, w))
+        });
+    let Some((input, output, cache_read, cache_write)) = prices else {
+        return 0.0;
+    };
+    (usage.input_tokens as f64 * input
+        + usage.output_tokens as f64 * output
+        + usage.cache_read_tokens as f64 * cache_read
+        + usage.cache_write_tokens as f64 * cache_write)
+        / 1_000_000.0
+}
+
+// Reads the usage of a finished run from the agent's own session logs and stores it in the
+// run record and the issue record. Runs that already have usage recorded are left alone.
+fn record_usage(git_root: &Path, branch: &str, run_dir: &Path, worktree_path: &Path) -> Result<()> {
+    // Headless runs already have the usage the agent reported
+    let mut run = RunRecord::load(run_dir)?;
+    if run.usage.is_none() {
+        let pricing = load_config(git_root)?.pricing;
+        let Some(usage) = collect_usage(&run, worktree_path, &pricing) else {
+            return Ok(());
+        };
+        run.usage = Some(usage);
+        run.save(run_dir)?;
+    }
+
+    let mut total = Usage::default();
+    for dir in list_runs(git_root, branch)? {
+        if let Some(usage) = RunRecord::load(&dir).ok().and_then(|r| r.usage) {
+            total.add(&usage);
+        }
+    }
+    let mut issue = IssueRecord::load(git_root, branch)?;
+    issue.usage = total;
+    issue.save(git_root, branch)
+}
+
+fn collect_usage(run: &RunRecord, worktree_path: &Path, pricing: &[PriceConfig]) -> Option<Usage> {
+    let home = PathBuf::from(env::var("HOME").ok()?);
+    // The agents key their logs by the resolved working directory
+    let cwd = fs::canonicalize(worktree_path).unwrap_or_else(|_| worktree_path.to_path_buf());
+    let until = run.ended_at.clone().unwrap_or_else(|| utc_timestamp(unix_now()));
+    // Log timestamps have milliseconds, so compare up to the seconds
+    let in_run = |ts: &str| {
+        let ts = ts.get(..19).unwrap_or(ts);
+        ts >= &run.started_at[..19.min(run.started_at.len())] && ts <= &until[..19.min(until.len())]
+    };
+
+    match run.agent.as_str() {
+        "claude" => {
+            let config_dir = env::var("CLAUDE_CONFIG_DIR").map(PathBuf::from).unwrap_or_else(|_| home.join(".claude"));
+            let project: String = cwd
+                .to_string_lossy()
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+                .collect();
+            claude_usage(&config_dir.join("projects").join(project), &in_run, pricing)
+        }
+        "gemini" => {
+            let hash = sha256_hex(&cwd.to_string_lossy())?;
+            gemini_usage(&home.join(".gemini").join("tmp").join(hash).join("chats"), &in_run, pricing)
+        }
+        _ => None,
+    }
+}
+
+// Claude Code logs every API response with its token usage to
+// ~/.claude/projects/<cwd>/<session>.jsonl. A response can span several lines.
+fn claude_usage(dir: &Path, in_run: &dyn Fn(&str) -> bool, pricing: &[PriceConfig]) -> Option<Usage> {
+    let mut seen = Vec::new();
+    let mut total = Usage::default();
+    let mut found = false;
+
+    for entry in fs::read_dir(dir).ok()?.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("jsonl") {
+            continue;
+        }
+        let Ok(data) = fs::read_to_string(&path) else {
+            continue;
+        };
+        for line in data.lines() {
+            let Ok(value) = serde_json::from_str::<serde_json::Value>(line) else {
+                continue;
+            };
+            let message = &value["message"];
+            let usage = &message["usage"];
+            if usage.is_null() || !value["timestamp"].as_str().is_some_and(in_run) {
+                continue;
+            }
+            if let Some(id) = message["id"].as_str() {
+                if seen.iter().any(|s| s == id) {
+                    continue;
+                }
+                seen.push(id.to_string());
+            }
+
+            let tokens = |key: &str| usage[key].as_u64().unwrap_or(0);
+            let model = message["model"].as_str().unwrap_or("");
+            let mut run_usage = Usage {
+                input_tokens: tokens("input_tokens"),
+                output_tokens: tokens("output_tokens"),
+                cache_read_tokens: tokens("cache_read_input_tokens"),
+                cache_write_tokens: tokens("cache_creation_input_tokens"),
+                models: vec![model.to_string()],
+                ..Default::default()
+            };
+            run_usage.cost_usd = match value["costUSD"].as_f64() {
+                Some(cost) => cost,
+                None => estimate_cost(pricing, model, &run_usage),
+            };
+            total.add(&run_usage);
+            found = true;
+        }
+    }
+
+    found.then_some(total)
+}
+
+// Gemini CLI saves each chat to ~/.gemini/tmp/<sha256 of cwd>/chats/session-*.json, with the
+// token counts of every model response.
+fn gemini_usage(dir: &Path, in_run: &dyn Fn(&str) -> bool, pricing: &[PriceConfig]) -> Option<Usage> {
+    let mut total = Usage::default();
+    let mut found = false;
+
+    for entry in fs::read_dir(dir).ok()?.filter_map(|e| e.ok()) {
+        let Ok(data) = fs::read_to_string(entry.path()) else {
+            continue;
+        };
+        let Ok(chat) = serde_json::from_str::<serde_json::Value>(&data) else {
+            continue;
+        };
+        let Some(messages) = chat["messages"].as_array() else {
+            continue;
+        };
+        for message in messages {
+            let tokens = &message["tokens"];
+            if tokens.is_null() || !message["timestamp"].as_str().is_some_and(in_run) {
+                continue;
+            }
+            let count = |key: &str| tokens[key].as_u64().unwrap_or(0);
+            let model = message["model"].as_str().unwrap_or("");
+            // Gemini counts cached tokens as part of the input, and thoughts separately from the output
+            let mut run_usage = Usage {
+                input_tokens: count("input").saturating_sub(count("cached")),
+                output_tokens: count("output") + count("thoughts"),
+                cache_read_tokens: count("cached"),
+                models: vec![model.to_string()],
+                ..Default::default()
+            };
+            run_usage.cost_usd = estimate_cost(pricing, model, &run_usage);
+            total.add(&run_usage);
+            found = true;
+        }
+    }
+
+    found.then_some(total)
+}
+
+fn sha256_hex(s: &str) -> Option<String> {
+    for (program, args) in [("sha256sum", &[][..]), ("shasum", &["-a", "256"][..])] {
+        let mut command = Command::new(program);
+        command.args(args);
+        if let Ok(output) = output_with_stdin(command, s)
+            && output.status.success()
+        {
+            return String::from_utf8_lossy(&output.stdout).split_whitespace().next().map(String::from);
+        }
+    }
+    None
+}
+
+// One row of the report: a recorded run with what is known about its issue
+#[derive(Serialize)]
+struct ReportRun {
+    issue: String,
+    branch: String,
+    agent: String,
+    model: String,
+    started_at: String,
+    duration_secs: u64,
+    usage: Usage,
+    outcome: String,
+    labels: Vec<String>,
+}
+
+#[derive(Default, Serialize)]
+struct ReportGroup {
+    key: String,
+    runs: usize,
+    issues: usize,
+    merged: usize,
+    duration_secs: u64,
+    input_tokens: u64,
+    output_tokens: u64,
+    cache_read_tokens: u64,
+    cache_write_tokens: u64,
+    cost_usd: f64,
+}
+
+// A commit with the trailers fuzemill's commit-msg hook adds, see install_commit_hooks
+#[derive(Serialize)]
+struct AuditedCommit {
+    commit: String,
+    // None for commits not authored by an agent
+    agent: Option<String>,
+    model: Option<String>,
+    issue: Option<String>,
+    subject: String,
+}
+
+fn handle_audit(range: String, all: bool, format: String) -> Result<()> {
+    if !["table", "json"].contains(&format.as_str()) {
+        bail!("Unknown format '{}'. Use table or json.", format);
+    }
+    let current_dir = env::current_dir().context("Failed to get current directory")?;
+    let git_root = find_git_root(&current_dir).context("Not in a git repository")?;
+
+    // One record per commit, with its fields separated by unit separators
+    let output = Command::new("git")
+        .arg("log")
+        .arg("--format=%h%x1f%s%x1f%(trailers:key=Co-authored-by,valueonly,separator=%x1d)%x1f%(trailers:key=Fuzemill-Issue,valueonly)%x1f%(trailers:key=Fuzemill-Model,valueonly)%x1e")
+        .arg(&range)
+        .arg("--")
+        .current_dir(&git_root)
+        .output()
+        .context("Failed to execute 'git log'")?;
+    if !output.status.success() {
+        bail!("git log {} failed: {}", range, String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    let agents: Vec<(&str, &str)> = ["claude", "gemini"].iter().filter_map(|a| agent_co_author(a).ok().map(|c| (*a, c))).collect();
+    let mut total = 0;
+    let mut commits = Vec::new();
+    for record in String::from_utf8_lossy(&output.stdout).split('\x1e') {
+        let fields: Vec<&str> = record.trim_start_matches('\n').split('\x1f').map(|f| f.trim()).collect();
+        let [commit, subject, co_authors, issue, model] = fields[..] else {
+            continue;
+        };
+        total += 1;
+        let agent = co_authors
+            .split('\x1d')
+            .find_map(|c| agents.iter().find(|(_, co_author)| c.trim().eq_ignore_ascii_case(co_author)).map(|(a, _)| a.to_string()));
+        let issue = Some(issue.to_string()).filter(|i| !i.is_empty());
+        // Commits with fuzemill's trailers but another co-author still came from a session
+        let agent = agent.or_else(|| issue.as_ref().map(|_| "unknown".to_string()));
+        if agent.is_none() && !all {
+            continue;
+        }
+        commits.push(AuditedCommit {
+            commit: commit.to_string(),
+            agent,
+            model: Some(model.to_string()).filter(|m| !m.is_empty()),
+            issue,
+            subject: subject.to_string(),
+        });
+    }
+
+    if format == "json" {
+        println!("{}", serde_json::to_string_pretty(&commits)?);
+        return Ok(());
+    }
+
+    let authored = commits.iter().filter(|c| c.agent.is_some()).count();
+    if !commits.is_empty() {
+        println!("{:<10} {:<8} {:<20} {:<10} SUBJECT", "COMMIT", "AGENT", "MODEL", "ISSUE");
+    }
+    for c in &commits {
+        println!(
+            "{:<10} {:<8} {:<20} {:<10} {}",
+            c.commit,
+            c.agent.as_deref().unwrap_or("-"),
+            c.model.as_deref().unwrap_or("-"),
+            c.issue.as_deref().unwrap_or("-"),
+            c.subject
+        );
+    }
+    if !commits.is_empty() {
+        println!();
+    }
+    println!("{} of {} commits in {} were authored by an agent.", authored, total, range);
+    Ok(())
+}
+
+fn handle_report(by: String, since: Option<String>, until: Option<String>, format: String, verbose: bool, backend: IssueBackend) -> Result<()> {
+    let current_dir = env::current_dir().context("Failed to get current directory")?;
+    let git_root = find_git_root(&current_dir).context("Not in a git repository")?;
+
+    if !["agent", "model", "label", "issue", "day", "month"].contains(&by.as_str()) {
+        bail!("Unknown grouping '{}'. Use agent, model, label, issue, day or month.", by);
+    }
+    if !["table", "csv", "json"].contains(&format.as_str()) {
+        bail!("Unknown format '{}'. Use table, csv or json.", format);
+    }
+
+    let labels = if by == "label" { fetch_issue_labels(&git_root, verbose, backend) } else { Vec::new() };
+    let pricing = load_config(&git_root)?.pricing;
+
+    let mut runs = Vec::new();
+    for branch in list_state_branches(&git_root)? {
+        let issue = IssueRecord::load(&git_root, &branch)?;
+        for run_dir in list_runs(&git_root, &branch)? {
+            let Ok(record) = RunRecord::load(&run_dir) else {
+                continue;
+            };
+            if since.as_ref().is_some_and(|s| record.started_at.as_str() < s.as_str())
+                || until.as_ref().is_some_and(|u| record.started_at.get(..u.len()).is_some_and(|d| d > u.as_str()))
+            {
+                continue;
+            }
+
+            // Runs that ended before usage tracking, or whose session was never finished by fuzemill.
+            // Only shown here; recording it is left to finishing the session.
+            let usage = match &record.usage {
+                Some(usage) => Some(usage.clone()),
+                None if record.ended_at.is_some() => issue_worktree_path(&git_root, &branch)
+                    .ok()
+                    .and_then(|worktree| collect_usage(&record, &worktree, &pricing)),
+                None => None,
+            };
+
+            let duration_secs = match (parse_utc_timestamp(&record.started_at), record.ended_at.as_deref().and_then(parse_utc_timestamp)) {
+                (Some(start), Some(end)) => end.saturating_sub(start),
+                _ => 0,
+            };
+            runs.push(ReportRun {
+                labels: labels
+                    .iter()
+                    .find(|(id, _)| *id == record.issue_id)
+                    .map(|(_, l)| l.clone())
+                    .unwrap_or_default(),
+                issue: record.issue_id,
+                branch: branch.clone(),
+                agent: record.agent,
+                // The model the agent reported using, if it was not chosen explicitly
+                model: record
+                    .model
+                    .or_else(|| usage.as_ref().filter(|u| !u.models.is_empty()).map(|u| u.models.join("+")))
+                    .unwrap_or_else(|| "default".to_string()),
+                started_at: record.started_at,
+                duration_secs,
+                usage: usage.unwrap_or_default(),
+                outcome: issue.outcome.clone().unwrap_or_else(|| "open".to_string()),
+            });
+        }
+    }
+
+    let mut groups: Vec<(ReportGroup, Vec<String>, Vec<String>)> = Vec::new();
+    for run in &runs {
+        let keys = match by.as_str() {
+            "agent" => vec![run.agent.clone()],
+            "model" => vec![format!("{}/{}", run.agent, run.model)],
+            "issue" => vec![run.issue.clone()],
+            "day" => vec![run.started_at.get(..10).unwrap_or("").to_string()],
+            "month" => vec![run.started_at.get(..7).unwrap_or("").to_string()],
+            _ if run.labels.is_empty() => vec!["(no label)".to_string()],
+            _ => run.labels.clone(),
+        };
+        for key in keys {
+            let index = match groups.iter().position(|(g, ..)| g.key == key) {
+                Some(i) => i,
+                None => {
+                    groups.push((ReportGroup { key, ..Default::default() }, Vec::new(), Vec::new()));
+                    groups.len() - 1
+                }
+            };
+            let (group, issues, merged) = &mut groups[index];
+            group.runs += 1;
+            group.duration_secs += run.duration_secs;
+            group.input_tokens += run.usage.input_tokens;
+            group.output_tokens += run.usage.output_tokens;
+            group.cache_read_tokens += run.usage.cache_read_tokens;
+            group.cache_write_tokens += run.usage.cache_write_tokens;
+            group.cost_usd += run.usage.cost_usd;
+            if !issues.contains(&run.issue) {
+                issues.push(run.issue.clone());
+            }
+            if run.outcome == "merged" && !merged.contains(&run.branch) {
+                merged.push(run.branch.clone());
+            }
+        }
+    }
+    let mut groups: Vec<ReportGroup> = groups
+        .into_iter()
+        .map(|(mut group, issues, merged)| {
+            group.issues = issues.len();
+            group.merged = merged.len();
+            group
+        })
+        .collect();
+    groups.sort_by(|a, b| a.key.cmp(&b.key));
+
+    match format.as_str() {
+        "json" => println!("{}", serde_json::to_string_pretty(&groups)?),
+        "csv" => {
+            println!("{},runs,issues,merged,duration_secs,input_tokens,output_tokens,cache_read_tokens,cache_write_tokens,cost_usd", by);
+            for g in &groups {
+                println!(
+                    "{},{},{},{},{},{},{},{},{},{:.4}",
+                    csv_field(&g.key),
+                    g.runs,
+                    g.issues,
+                    g.merged,
+                    g.duration_secs,
+                    g.input_tokens,
+                    g.output_tokens,
+                    g.cache_read_tokens,
+                    g.cache_write_tokens,
+                    g.cost_usd
+                );
+            }
+        }
+        _ => {
+            if groups.is_empty() {
+                println!("No recorded runs.");
+                return Ok(());
+            }
+            println!("{:<24} {:>5} {:>7} {:>7} {:>9} {:>12} {:>12} {:>10}", by.to_uppercase(), "RUNS", "ISSUES", "MERGED", "DURATION", "INPUT", "OUTPUT", "COST");
+            for g in &groups {
+                println!(
+                    "{:<24} {:>5} {:>7} {:>7} {:>9} {:>12} {:>12} {:>10}",
+                    g.key,
+                    g.runs,
+                    g.issues,
+                    g.merged,
+                    format!("{}h{:02}m", g.duration_secs / 3600, g.duration_secs % 3600 / 60),
+                    g.input_tokens + g.cache_read_tokens + g.cache_write_tokens,
+                    g.output_tokens,
+                    format!("${:.2}", g.cost_usd)
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+// Labels of all issues, for grouping the report. Only GitHub Issues have labels.
+fn fetch_issue_labels(cwd: &Path, verbose: bool, backend: IssueBackend) -> Vec<(String, Vec<String>)> {
+    if backend == IssueBackend::Beads {
+        if verbose {
+            eprintln!("Warning: Grouping by label is only supported with GitHub Issues");
+        }
+        return Vec::new();
+    }
+
+    let output = Command::new("gh")
+        .arg("issue")
+        .arg("list")
+        .arg("--state")
+        .arg("all")
+        .arg("--limit")
+        .arg("1000")
+        .arg("--json")
+        .arg("number,labels")
+        .arg("--jq")
+        .arg(".[] | [(.number | tostring), ([.labels[].name] | join(\",\"))] | @tsv")
+        .current_dir(cwd)
+        .output();
+
+    match output {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|l| l.split_once('\t'))
+            .map(|(number, labels)| {
+                let labels = labels
+                    .split(',')
+                    .filter(|l| !l.is_empty() && !l.starts_with("status:"))
+                    .map(String::from)
+                    .collect();
+                (number.to_string(), labels)
+            })
+            .collect(),
+        _ => {
+            eprintln!("Warning: Failed to fetch issue labels with gh");
+            Vec::new()
+        }
+    }
+}
+
+fn create_new_issue(args: &[String], cwd: &Path, backend: IssueBackend) -> Result<String> {
+    match backend {
+        IssueBackend::Beads => create_new_issue_beads(args, cwd),
+        IssueBackend::GitHub => create_new_issue_github(args, cwd),
+    }
+}
+
+fn create_new_issue_beads(args: &[String], cwd: &Path) -> Result<String> {
+    let output = Command::new("bd")
+        .arg("create")
+        .args(args)
+        .arg("--silent")
+        .current_dir(cwd)
+        .output()
+        .context("Failed to execute 'bd create'")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        eprintln!("{}", stderr);
+        bail!("Failed to create issue.");
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let issue_id = stdout.trim().to_string();
+
+    if issue_id.is_empty() {
+        bail!("'bd create' returned empty issue ID.");
+    }
+
+    println!("Created issue: {}", issue_id.green());
+    Ok(issue_id)
+}
+
+fn create_new_issue_github(args: &[String], cwd: &Path) -> Result<String> {
+    if args.is_empty() {
+        bail!("Please provide a title for the issue.");
+    }
+
+    let mut gh_args = vec!["issue", "create"];
+
+    // Check if args use flags or positional
+    // If first arg starts with '-', treat as flags (compatible with gh)
+    // Otherwise, treat first arg as title
+    if !args[0].starts_with('-') {
+        gh_args.push("--title");
+        // We need to handle the title argument carefully
+    }
+
+    let output = if !args[0].starts_with('-') {
+        // Positional: first arg is title, rest is body
+        let mut cmd = Command::new("gh");
+        cmd.arg("issue")
+            .arg("create")
+            .arg("--title")
+            .arg(&args[0]);
+
+        if args.len() > 1 {
+            let body = args[1..].join(" ");
+            cmd.arg("--body").arg(&body);
+        }
+
+        cmd.current_dir(cwd)
+            .output()
+            .context("Failed to execute 'gh issue create'")?
+    } else {
+        // Flags: pass through (bd and gh use similar flags: -t for title, -b for body)
+        Command::new("gh")
+            .arg("issue")
+            .arg("create")
+            .args(args)
+            .current_dir(cwd)
+            .output()
+            .context("Failed to execute 'gh issue create'")?
+    };
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        eprintln!("{}", stderr);
+        bail!("Failed to create GitHub issue.");
+    }
+
+    // gh issue create outputs URL like: https://github.com/owner/repo/issues/123
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let url = stdout.trim();
+
+    // Extract issue number from URL
+    let issue_id = url
+        .rsplit('/')
+        .next()
+        .context("Failed to parse issue number from gh output")?
+        .to_string();
+
+    if issue_id.is_empty() || !issue_id.chars().all(|c| c.is_ascii_digit()) {
+        bail!("Failed to extract issue number from: {}", url);
+    }
+
+    println!("Created GitHub issue: {} ({})", issue_id.green(), url);
+    Ok(issue_id)
+}
+
+fn handle_unstart(issue_id: String, verbose: bool) -> Result<()> {
+    let current_dir = env::current_dir().context("Failed to get current directory")?;
+    let git_root = find_git_root(&current_dir).context("Not in a git repository")?;
+    
+    let (main_repo_path, is_worktree) = get_git_common_dir(&git_root)?;
+
+    // Construct expected path for the issue
+    // We need to guess where it is. Assuming sibling convention used in Start.
+    // If we are IN the worktree to be deleted, we know the path is git_root.
+    
+    // Case 1: We are inside the worktree we want to delete
+    // We verify if the branch matches the issue_id
+    let current_branch = get_current_branch(&git_root)?;
+    
+    let worktree_to_remove;
+    let branch_to_remove = issue_id.clone(); // Assume branch name is issue_id
+
+    if is_worktree && current_branch == issue_id {
+        worktree_to_remove = git_root.clone();
+        if verbose {
+            println!("Detected we are inside the worktree to remove.");
+        }
+        
+        // We need to move out before deleting.
+        // Move to main repo.
+        env::set_current_dir(&main_repo_path).context("Failed to change directory to main repo")?;
+        println!("Moved to main repo: {}", main_repo_path.display());
+    } else {
+        // Case 2: We are outside (maybe in main), asking to delete a sibling worktree
+        // We reconstruct the path
+        let repo_dirname = main_repo_path.file_name().and_then(|n| n.to_str()).unwrap_or("unknown");
+        let dir_name = format!("{}-{}", repo_dirname, issue_id);
+        // Assuming sibling of main repo
+        let probable_path = main_repo_path.parent().unwrap().join(dir_name);
+        
+        if probable_path.exists() {
+            worktree_to_remove = probable_path;
+        } else {
+            // Try to look it up via 'git worktree list'?
+            // For now, fail if not found at expected location
+             bail!("Could not find worktree at expected path: {}", probable_path.display());
+        }
+    }
+
+    run_hook(&main_repo_path, "pre-unstart", &issue_id, &branch_to_remove, &worktree_to_remove, verbose)?;
+
+    if verbose {
+        println!("Removing worktree: {}", worktree_to_remove.display());
+    }
+
+    // git worktree remove <path>
+    let status = Command::new("git")
+        .arg("worktree")
+        .arg("remove")
+        .arg(&worktree_to_remove)
+        .status()
+        .context("Failed to execute git worktree remove")?;
+
+    if !status.success() {
+        // Sometimes force is needed if modified files?
+        // For now, let it fail.
+        bail!("git worktree remove failed");
+    }
+
+    // git branch -D <issue_id>
+    let status = Command::new("git")
+        .arg("branch")
+        .arg("-D")
+        .arg(&branch_to_remove)
+        .status()
+        .context("Failed to delete branch")?;
+
+    if !status.success() {
+        println!("{}", "Warning: Failed to delete branch (maybe it was already deleted or different name?)".yellow());
+    } else {
+        println!("Deleted branch {}", branch_to_remove);
+    }
+    set_issue_outcome(&main_repo_path, &branch_to_remove, "abandoned");
+
+    run_hook(&main_repo_path, "post-unstart", &issue_id, &branch_to_remove, &worktree_to_remove, verbose)?;
+    
+    // If we were inside the worktree, we are now in main_repo (due to set_current_dir).
+    // We should spawn a shell there so the user feels "cd'ed back".
+    if is_worktree && current_branch == issue_id {
+        println!("Spawning subshell in {}", main_repo_path.display().to_string().green());
+        spawn_shell(&main_repo_path)?;
+    }
+
+    Ok(())
+}
+
+enum GcAction {
+    KillSession(SessionHost, String),
+    RemoveWorktree(PathBuf),
+    PruneWorktrees,
+    RemoveDir(PathBuf),
+    DeleteBranch(String),
+    ClearStatus(String, String),
+}
+
+impl GcAction {
+    fn describe(&self) -> String {
+        match self {
+            GcAction::KillSession(host, name) => format!("kill {} session '{}' (its worktree is gone)", host.name(), name),
+            GcAction::RemoveWorktree(path) => format!("remove worktree {} (PR merged, no session)", path.display()),
+            GcAction::PruneWorktrees => "prune stale worktree entries (directories already deleted)".to_string(),
+            GcAction::RemoveDir(path) => format!("delete directory {} (not a registered worktree)", path.display()),
+            GcAction::DeleteBranch(branch) => format!("delete local branch '{}' (PR merged)", branch),
+            GcAction::ClearStatus(issue_id, label) => format!("remove label '{}' from issue #{} (no session or worktree)", label, issue_id),
+        }
+    }
+}
+
+fn handle_gc(yes: bool, verbose: bool, backend: IssueBackend) -> Result<()> {
+    let current_dir = env::current_dir().context("Failed to get current directory")?;
+    let git_root = find_git_root(&current_dir).context("Not in a git repository")?;
+    let (main_repo_path, _) = get_git_common_dir(&git_root)?;
+
+    let repo_name = main_repo_path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .context("Invalid repository path")?;
+    let prefix = format!("{}-", repo_name);
+    let worktree_parent = main_repo_path.parent().context("Cannot find parent of git root")?;
+
+    if verbose {
+        println!("Collecting worktrees, branches and sessions...");
+    }
+
+    let worktrees = list_worktrees(&main_repo_path)?;
+    let merged_branches = list_merged_pr_branches(&main_repo_path)?;
+    let current_branch = get_current_branch(&git_root)?;
+
+    let mut actions = Vec::new();
+    let mut skipped = Vec::new();
+
+    // Sessions whose worktree directory no longer exists. Sessions are shared by all
+    // repositories, so only the ones started in this repository's worktrees are touched.
+    for host in SessionHost::MULTIPLEXERS {
+        for name in host.list() {
+            let Some(branch) = name.strip_prefix("fuzemill-") else {
+                continue;
+            };
+            let worktree_path = issue_worktree_path(&main_repo_path, branch)?;
+            if is_orphaned_session(host.session_path(&name).as_deref(), &worktree_path, worktree_path.exists()) {
+                actions.push(GcAction::KillSession(host, name));
+            }
+        }
+    }
+
+    // Registered fuzemill worktrees with a merged PR and no running session
+    let mut removed_worktrees = Vec::new();
+    let mut stale_entries = false;
+    for (path, branch) in &worktrees {
+        if !path.exists() {
+            stale_entries = true;
+            continue;
+        }
+        let Some(issue_id) = path.file_name().and_then(|n| n.to_str()).and_then(|n| n.strip_prefix(&prefix)) else {
+            continue;
+        };
+        let Some(branch) = branch else {
+            continue;
+        };
+        if !merged_branches.contains(branch) || session_host(&main_repo_path, issue_id).is_alive(&format!("fuzemill-{}", issue_id)) {
+            continue;
+        }
+        if worktree_has_changes(path)? {
+            skipped.push(format!("worktree {} has uncommitted changes", path.display()));
+            continue;
+        }
+        removed_worktrees.push(path.clone());
+        actions.push(GcAction::RemoveWorktree(path.clone()));
+    }
+
+    if stale_entries {
+        actions.push(GcAction::PruneWorktrees);
+    }
+
+    // '<repo>-<issue>' directories left behind by a worktree git no longer knows about
+    let registered: Vec<PathBuf> = worktrees.iter().filter_map(|(p, _)| p.canonicalize().ok()).collect();
+    let worktrees_dir = main_repo_path.join(".git").canonicalize().context("Failed to resolve git directory")?.join("worktrees");
+    for entry in fs::read_dir(worktree_parent).context("Failed to read worktree parent directory")? {
+        let path = entry?.path();
+        let is_candidate = path.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with(&prefix));
+        if !is_candidate || !path.join(".git").is_file() {
+            continue;
+        }
+        let Ok(canonical) = path.canonicalize() else {
+            continue;
+        };
+        if registered.contains(&canonical) {
+            continue;
+        }
+        if !linked_gitdir(&path).is_some_and(|gitdir| gitdir.starts_with(&worktrees_dir)) {
+            skipped.push(format!("{} does not belong to this repository", path.display()));
+            continue;
+        }
+        match worktree_has_changes(&path) {
+            Ok(false) => actions.push(GcAction::RemoveDir(path)),
+            Ok(true) => skipped.push(format!("{} has uncommitted changes", path.display())),
+            Err(_) => skipped.push(format!("{} could not be checked for changes", path.display())),
+        }
+    }
+
+    // Local branches whose PR was merged, unless still checked out or holding unpushed work
+    for branch in list_local_branches(&main_repo_path)? {
+        if branch == current_branch || !merged_branches.contains(&branch) {
+            continue;
+        }
+        let checked_out = worktrees
+            .iter()
+            .any(|(p, b)| b.as_deref() == Some(branch.as_str()) && !removed_worktrees.contains(p));
+        if checked_out {
+            continue;
+        }
+        let unpushed = count_unpushed_commits(&main_repo_path, &branch)?;
+        if unpushed > 0 {
+            skipped.push(format!("branch '{}' has {} unpushed commit(s)", branch, unpushed));
+            continue;
+        }
+        actions.push(GcAction::DeleteBranch(branch));
+    }
+
+    // Issues still labelled as being worked on, with nothing left working on them
+    match backend {
+        IssueBackend::GitHub => {
+            for label in ["status:hooked", "status:in_progress"] {
+                for issue_id in list_issues_with_label(&main_repo_path, label)? {
+                    let has_session = session_host(&main_repo_path, &issue_id).is_alive(&format!("fuzemill-{}", issue_id));
+                    let has_worktree = worktree_parent.join(format!("{}{}", prefix, issue_id)).exists();
+                    if !has_session && !has_worktree {
+                        actions.push(GcAction::ClearStatus(issue_id, label.to_string()));
+                    }
+                }
+            }
+        }
+        IssueBackend::Beads => {
+            if verbose {
+                println!("Skipping stale status check (only supported for GitHub Issues).");
+            }
+        }
+    }
+
+    for reason in &skipped {
+        println!("{} {}", "Keeping:".yellow(), reason);
+    }
+
+    if actions.is_empty() {
+        println!("{}", "Nothing to clean up.".green());
+        return Ok(());
+    }
+
+    println!("The following will be cleaned up:");
+    for action in &actions {
+        println!("  - {}", action.describe());
+    }
+
+    if !yes && !confirm("Proceed?")? {
+        println!("Aborted.");
+        return Ok(());
+    }
+
+    let mut failures = 0;
+    for action in &actions {
+        if let Err(e) = run_gc_action(action, &main_repo_path, verbose) {
+            eprintln!("Warning: Failed to {}: {}", action.describe(), e);
+            failures += 1;
+        }
+    }
+
+    if failures > 0 {
+        bail!("{} of {} cleanup steps failed", failures, actions.len());
+    }
+    println!("Cleaned up {} item(s).", actions.len());
+    Ok(())
+}
+
+// A session is orphaned once the worktree it was started in is gone. Without a known
+// session path it may well belong to another repository's worktree.
+fn is_orphaned_session(session_path: Option<&Path>, worktree_path: &Path, worktree_exists: bool) -> bool {
+    !worktree_exists && session_path == Some(worktree_path)
+}
+
+fn run_gc_action(action: &GcAction, main_repo_path: &Path, verbose: bool) -> Result<()> {
+    if verbose {
+        println!("Running: {}", action.describe());
+    }
+
+    let output = match action {
+        GcAction::KillSession(host, name) => return host.kill(name),
+        GcAction::RemoveWorktree(path) => Command::new("git")
+            .arg("worktree")
+            .arg("remove")
+            .arg(path)
+            .current_dir(main_repo_path)
+            .output()
+            .context("Failed to execute git worktree remove")?,
+        GcAction::PruneWorktrees => Command::new("git")
+            .arg("worktree")
+            .arg("prune")
+            .current_dir(main_repo_path)
+            .output()
+            .context("Failed to execute git worktree prune")?,
+        GcAction::RemoveDir(path) => {
+            fs::remove_dir_all(path).with_context(|| format!("Failed to delete {}", path.display()))?;
+            return Ok(());
+        }
+        GcAction::DeleteBranch(branch) => Command::new("git")
+            .arg("branch")
+            .arg("-D")
+            .arg(branch)
+            .current_dir(main_repo_path)
+            .output()
+            .context("Failed to delete branch")?,
+        GcAction::ClearStatus(issue_id, label) => Command::new("gh")
+            .arg("issue")
+            .arg("edit")
+            .arg(issue_id)
+            .arg("--remove-label")
+            .arg(label)
+            .current_dir(main_repo_path)
+            .output()
+            .context("Failed to execute 'gh issue edit'")?,
+    };
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("{}", stderr.trim());
+    }
+    Ok(())
+}
+
+fn confirm(prompt: &str) -> Result<bool> {
+    print!("{} [y/N] ", prompt);
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).context("Failed to read answer")?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+// Returns (path, branch) for every worktree git knows about, including the main one
+fn list_worktrees(main_repo_path: &Path) -> Result<Vec<(PathBuf, Option<String>)>> {
+    let output = Command::new("git")
+        .arg("worktree")
+        .arg("list")
+        .arg("--porcelain")
+        .current_dir(main_repo_path)
+        .output()
+        .context("Failed to execute git worktree list")?;
+
+    if !output.status.success() {
+        bail!("git worktree list failed");
+    }
+
+    let mut worktrees: Vec<(PathBuf, Option<String>)> = Vec::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        if let Some(path) = line.strip_prefix("worktree ") {
+            worktrees.push((PathBuf::from(path), None));
+        } else if let (Some(branch), Some(last)) = (line.strip_prefix("branch refs/heads/"), worktrees.last_mut()) {
+            last.1 = Some(branch.to_string());
+        }
+    }
+    Ok(worktrees)
+}
+
+fn list_local_branches(main_repo_path: &Path) -> Result<Vec<String>> {
+    let output = Command::new("git")
+        .arg("for-each-ref")
+        .arg("--format=%(refname:short)")
+        .arg("refs/heads")
+        .current_dir(main_repo_path)
+        .output()
+        .context("Failed to list local branches")?;
+
+    if !output.status.success() {
+        bail!("git for-each-ref failed");
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).lines().map(|l| l.to_string()).collect())
+}
+
+fn count_unpushed_commits(main_repo_path: &Path, branch: &str) -> Result<usize> {
+    let output = Command::new("git")
+        .arg("rev-list")
+        .arg("--count")
+        .arg(branch)
+        .arg("--not")
+        .arg("--remotes")
+        .current_dir(main_repo_path)
+        .output()
+        .context("Failed to execute 'git rev-list'")?;
+
+    if !output.status.success() {
+        bail!("git rev-list failed for branch '{}'", branch);
+    }
+
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .context("Failed to parse 'git rev-list --count' output")
+}
+
+fn list_merged_pr_branches(cwd: &Path) -> Result<Vec<String>> {
+    let output = Command::new("gh")
+        .arg("pr")
+        .arg("list")
+        .arg("--state")
+        .arg("merged")
+        .arg("--limit")
+        .arg("200")
+        .arg("--json")
+        .arg("headRefName")
+        .arg("--jq")
+        .arg(".[].headRefName")
+        .current_dir(cwd)
+        .output()
+        .context("Failed to execute 'gh pr list'. Is gh CLI installed and authenticated?")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("gh pr list failed: {}", stderr.trim());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).lines().map(|l| l.to_string()).collect())
+}
+
+fn list_issues_with_label(cwd: &Path, label: &str) -> Result<Vec<String>> {
+    let output = Command::new("gh")
+        .arg("issue")
+        .arg("list")
+        .arg("--state")
+        .arg("open")
+        .arg("--label")
+        .arg(label)
+        .arg("--json")
+        .arg("number")
+        .arg("--jq")
+        .arg(".[].number")
+        .current_dir(cwd)
+        .output()
+        .context("Failed to execute 'gh issue list'")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("gh issue list failed: {}", stderr.trim());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).lines().map(|l| l.to_string()).collect())
+}
+
+fn spawn_shell(path: &Path) -> Result<()> {
+    let shell = env::var("SHELL").unwrap_or_else(|_| "sh".to_string());
+    let mut command = Command::new(shell);
+    command.current_dir(path);
+    
+    let status = command.status().context("Failed to spawn shell")?;
+    
+    if !status.success() {
+        bail!("Shell exited with non-zero status");
+    }
+    Ok(())
+}
+
+fn get_current_branch(cwd: &Path) -> Result<String> {
+    let output = Command::new("git")
+        .arg("rev-parse")
+        .arg("--abbrev-ref")
+        .arg("HEAD")
+        .current_dir(cwd)
+        .output()
+        .context("Failed to get current branch")?;
+        
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// Returns (main_repo_path, is_worktree)
+fn get_git_common_dir(git_root: &Path) -> Result<(PathBuf, bool)> {
+    // Check if .git is a file (worktree) or dir (main repo)
+    let git_item = git_root.join(".git");
+    if git_item.is_file() {
+        // It's a worktree. 
+        // We can find the main dir by parsing the .git file or asking git
+        let output = Command::new("git")
+            .arg("rev-parse")
+            .arg("--path-format=absolute")
+            .arg("--git-common-dir")
+            .current_dir(git_root)
+            .output()
+            .context("Failed to get git common dir")?;
+            
+        let common_dir_str = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        let common_dir = PathBuf::from(common_dir_str);
+        
+        // common_dir usually points to .git inside main repo. Parent is main repo.
+        let main_repo = common_dir.parent().unwrap_or(&common_dir).to_path_buf();
+        Ok((main_repo, true))
+    } else {
+        Ok((git_root.to_path_buf(), false))
+    }
+}
+
+// fuzemill's per-repository state lives in the git common dir, so it is shared by all
+// worktrees and never shows up in commits.
+fn state_dir(git_root: &Path) -> Result<PathBuf> {
+    let output = Command::new("git")
+        .arg("rev-parse")
+        .arg("--path-format=absolute")
+        .arg("--git-common-dir")
+        .current_dir(git_root)
+        .output()
+        .context("Failed to get git common dir")?;
+
+    if !output.status.success() {
+        bail!("git rev-parse --git-common-dir failed");
+    }
+
+    let common_dir = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+    Ok(common_dir.join("fuzemill"))
+}
+
+// Worktrees are siblings of the main repository, named <repo>-<issue>
+fn issue_worktree_path(git_root: &Path, issue_id: &str) -> Result<PathBuf> {
+    let (main_repo_path, _) = get_git_common_dir(git_root)?;
+    let repo_name = main_repo_path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .context("Invalid repository path")?;
+    let parent = main_repo_path.parent().context("Cannot find parent of git root")?;
+    Ok(parent.join(format!("{}-{}", repo_name, issue_id)))
+}
+
+fn issue_state_dir(git_root: &Path, issue_id: &str) -> Result<PathBuf> {
+    Ok(state_dir(git_root)?.join("issues").join(issue_id))
+}
+
+// Each agent session gets its own run directory, named after its start time
+fn create_run_dir(git_root: &Path, issue_id: &str) -> Result<PathBuf> {
+    let runs_dir = issue_state_dir(git_root, issue_id)?.join("runs");
+    let run_id = utc_timestamp(unix_now()).replace(['-', ':'], "");
+
+    let mut run_dir = runs_dir.join(&run_id);
+    let mut n = 1;
+    while run_dir.exists() {
+        n += 1;
+        run_dir = runs_dir.join(format!("{}-{}", run_id, n));
+    }
+
+    fs::create_dir_all(&run_dir).with_context(|| format!("Failed to create {}", run_dir.display()))?;
+    Ok(run_dir)
+}
+
+// Per-repository settings, read from .fuzemill.toml in the main repository
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    pr: PrConfig,
+    checks: Vec<CheckConfig>,
+    bootstrap: BootstrapConfig,
+    hooks: HooksConfig,
+    notify: NotifyConfig,
+    limits: LimitsConfig,
+    pricing: Vec<PriceConfig>,
+    runner: RunnerConfig,
+    sandbox: SandboxConfig,
+    permissions: PermissionsConfig,
+    instructions: InstructionsConfig,
+    identity: IdentitiesConfig,
+    queue: QueueConfig,
+}
+
+impl Config {
+    // The runner, if the permission policy can be used with it
+    fn resolve_runner(&self) -> Result<Runner> {
+        let runner = self.runner.resolve()?;
+        if runner == Runner::Headless && self.permissions.mode == PermissionMode::Ask {
+            bail!("The 'ask' permission policy needs someone to answer the agent; use 'allowlist' for headless runs.");
+        }
+        Ok(runner)
+    }
+}
+
+// Which issues 'next' and 'run-queue' pick, and how many agents run at once
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct QueueConfig {
+    // Only issues with this label are started; empty for any issue
+    label: String,
+    concurrency: usize,
+    agent: String,
+    // GitHub labels from highest to lowest priority; Beads has its own priorities
+    priority_labels: Vec<String>,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            label: "agent-ready".to_string(),
+            concurrency: 2,
+            agent: "claude".to_string(),
+            priority_labels: ["P0", "P1", "P2", "P3"].iter().map(|l| l.to_string()).collect(),
+        }
+    }
+}
+
+// Git identity and signing of each agent's commits, see apply_agent_identity
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct IdentitiesConfig {
+    claude: IdentityConfig,
+    gemini: IdentityConfig,
+}
+
+impl IdentitiesConfig {
+    fn for_agent(&self, agent: &str) -> &IdentityConfig {
+        match agent {
+            "gemini" => &self.gemini,
+            _ => &self.claude,
+        }
+    }
+}
+
+// Unset fields keep the user's own git config
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct IdentityConfig {
+    name: Option<String>,
+    email: Option<String>,
+    signing: Signing,
+    // Public key file of a dedicated SSH key, for signing = "key"
+    signing_key: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Signing {
+    // Sign as the user's git config says
+    #[default]
+    Inherit,
+    // Sign with signing_key, through SSH
+    Key,
+    // Don't sign
+    None,
+    // Don't sign; 'merge' squash-merges, so the commit that lands is signed by GitHub
+    Merge,
+}
+
+// The fuzemill section of the agent's instruction file, see write_agent_instructions
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct InstructionsConfig {
+    enabled: bool,
+    // Defaults to CLAUDE.md or GEMINI.md, depending on the agent
+    file: Option<String>,
+    template: String,
+}
+
+impl Default for InstructionsConfig {
+    fn default() -> Self {
+        InstructionsConfig {
+            enabled: true,
+            file: None,
+            template: DEFAULT_INSTRUCTIONS.to_string(),
+        }
+    }
+}
+
+const DEFAULT_INSTRUCTIONS: &str = "## Workflow
+
+You are working on issue {issue} ({title}) on branch '{branch}'. Run '{issue_view}' for the details.
+
+- Commit your changes with a descriptive message and add 'Co-authored-by: {co_author}' to every commit message.
+- {pr}
+{checks}- When you are finished, run '{done}' to close the session.
+";
+
+// What the agent may do without asking, see write_agent_permissions
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PermissionsConfig {
+    mode: PermissionMode,
+    // Shell commands allowed with any arguments, e.g. "cargo test"
+    commands: Vec<String>,
+    // Files the agent may edit, as globs relative to the worktree (Claude only)
+    paths: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum PermissionMode {
+    // The agent does anything without asking
+    #[default]
+    Yolo,
+    // The agent asks before anything beyond fuzemill's workflow
+    Ask,
+    // The agent asks before anything not in the allowlist
+    Allowlist,
+}
+
+// How agent sessions are run. FUZEMILL_RUNNER overrides the mode, e.g. on a build box.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RunnerConfig {
+    mode: Runner,
+    // Headless runs are stopped after this long
+    timeout_minutes: Option<u64>,
+}
+
+impl RunnerConfig {
+    fn resolve(&self) -> Result<Runner> {
+        match env::var("FUZEMILL_RUNNER").ok().as_deref() {
+            None | Some("") => Ok(self.mode),
+            Some("tmux") => Ok(Runner::Tmux),
+            Some("zellij") => Ok(Runner::Zellij),
+            Some("screen") => Ok(Runner::Screen),
+            Some("headless") => Ok(Runner::Headless),
+            Some("foreground") => Ok(Runner::Foreground),
+            Some(other) => bail!("Unknown runner '{}' in FUZEMILL_RUNNER. Use 'tmux', 'zellij', 'screen', 'headless' or 'foreground'.", other),
+        }
+    }
+}
+
+// Where the agent runs, independent of which agent it is
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Runner {
+    // Detached session in a terminal multiplexer that fuzemill attaches to
+    #[default]
+    Tmux,
+    Zellij,
+    Screen,
+    // Non-interactive child process with its output in a log file, for CI and cron
+    Headless,
+    // Interactive agent in the current terminal
+    Foreground,
+}
+
+impl Runner {
+    // None for headless runs, which have no terminal
+    fn host(self) -> Option<SessionHost> {
+        match self {
+            Runner::Tmux => Some(SessionHost::Tmux),
+            Runner::Zellij => Some(SessionHost::Zellij),
+            Runner::Screen => Some(SessionHost::Screen),
+            Runner::Headless => None,
+            Runner::Foreground => Some(SessionHost::Foreground),
+        }
+    }
+}
+
+// Prices in USD per million tokens for models whose name contains `model`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PriceConfig {
+    model: String,
+    input: f64,
+    output: f64,
+    #[serde(default)]
+    cache_read: f64,
+    #[serde(default)]
+    cache_write: f64,
+}
+
+// Per-session limits, enforced by 'watch'
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LimitsConfig {
+    wall_clock_minutes: Option<u64>,
+    idle_minutes: Option<u64>,
+    max_commits: Option<u32>,
+    policy: LimitPolicy,
+}
+
+// What happens to a session that exceeds a limit
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LimitPolicy {
+    // Send Ctrl-C to the agent and keep the session
+    #[default]
+    Interrupt,
+    // Commit the uncommitted work, end the session and mark the issue blocked
+    Salvage,
+    // End the session and mark the issue blocked
+    End,
+}
+
+// Confines the agent to its worktree, see handle_sandbox. Paths may start with '~/'.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SandboxConfig {
+    mode: SandboxMode,
+    network: SandboxNetwork,
+    // Hosts reachable with the allowlist; '*.example.com' matches subdomains
+    allow: Vec<String>,
+    // Toolchain directories in the home directory, mounted read-only
+    read_only: Vec<String>,
+    // Paths mounted writable, such as the agent's login state. Only what is listed gets in.
+    writable: Vec<String>,
+    // Environment variables passed in, such as API keys
+    env: Vec<String>,
+    // Container image for podman
+    image: Option<String>,
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        SandboxConfig {
+            mode: SandboxMode::None,
+            network: SandboxNetwork::Allowlist,
+            allow: vec!["api.anthropic.com".to_string(), "*.googleapis.com".to_string()],
+            read_only: ["~/.cargo", "~/.rustup", "~/.local/bin", "~/.local/share/claude", "~/.npm-global", "~/.nvm", "~/.bun", "~/go"]
+                .iter()
+                .map(|p| p.to_string())
+                .collect(),
+            writable: Vec::new(),
+            env: Vec::new(),
+            image: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SandboxMode {
+    // The agent runs directly on the host
+    #[default]
+    None,
+    // bubblewrap, with the host's toolchain
+    Bwrap,
+    // Rootless podman container, with the image's toolchain
+    Podman,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SandboxNetwork {
+    // HTTPS to the allowed hosts only, through fuzemill's proxy
+    #[default]
+    Allowlist,
+    None,
+    Host,
+}
+
+// Where 'watch' sends notifications
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct NotifyConfig {
+    // Terminal bell and OSC 9 in the terminal running 'watch'
+    bell: bool,
+    // Desktop notification through notify-send
+    desktop: bool,
+    // URL that gets a JSON POST for every notification
+    webhook: Option<String>,
+    // Notify when an agent has produced no output for this long; 0 disables it
+    idle_minutes: u64,
+    // Events to notify about; all of them if empty
+    events: Vec<String>,
+}
+
+impl Default for NotifyConfig {
+    fn default() -> Self {
+        NotifyConfig {
+            bell: true,
+            desktop: false,
+            webhook: None,
+            idle_minutes: 10,
+            events: Vec::new(),
+        }
+    }
+}
+
+// Steps that prepare a new worktree before the agent starts. Paths are relative to the
+// repository root.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct BootstrapConfig {
+    // Untracked files or directories copied from the main repository (e.g. .env)
+    copy: Vec<String>,
+    // Untracked files or directories linked to the main repository's copy (e.g. node_modules)
+    symlink: Vec<String>,
+    submodules: bool,
+    // Shell commands run in the worktree after the steps above
+    run: Vec<String>,
+}
+
+// Shell commands run at lifecycle events, see run_hook
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct HooksConfig {
+    pre_start: Option<String>,
+    post_start: Option<String>,
+    post_worktree: Option<String>,
+    pre_agent: Option<String>,
+    post_agent: Option<String>,
+    pre_done: Option<String>,
+    post_done: Option<String>,
+    pre_merge: Option<String>,
+    post_merge: Option<String>,
+    pre_unstart: Option<String>,
+    post_unstart: Option<String>,
+}
+
+impl HooksConfig {
+    fn command(&self, event: &str) -> Option<&str> {
+        let command = match event {
+            "pre-start" => &self.pre_start,
+            "post-start" => &self.post_start,
+            "post-worktree" => &self.post_worktree,
+            "pre-agent" => &self.pre_agent,
+            "post-agent" => &self.post_agent,
+            "pre-done" => &self.pre_done,
+            "post-done" => &self.post_done,
+            "pre-merge" => &self.pre_merge,
+            "post-merge" => &self.post_merge,
+            "pre-unstart" => &self.pre_unstart,
+            "post-unstart" => &self.post_unstart,
+            _ => &None,
+        };
+        command.as_deref()
+    }
+}
+
+// A command that has to pass before the session can end, e.g. `cargo test`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CheckConfig {
+    // Defaults to the command itself
+    name: Option<String>,
+    run: String,
+}
+
+impl CheckConfig {
+    fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.run)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PrConfig {
+    // When false, the agent is asked to push and open the PR itself
+    create: bool,
+    draft: bool,
+    title: String,
+    body: String,
+}
+
+impl Default for PrConfig {
+    fn default() -> Self {
+        PrConfig {
+            create: true,
+            draft: false,
+            title: "{title}".to_string(),
+            body: DEFAULT_PR_BODY.to_string(),
         }
+    }
+}
 
-        cmd.current_dir(cwd)
-            .output()
-            .context("Failed to execute 'gh issue create'")?
-    } else {
-        // Flags: pass through (bd and gh use similar flags: -t for title, -b for body)
-        Command::new("gh")
-            .arg("issue")
-            .arg("create")
-            .args(args)
-            .current_dir(cwd)
-            .output()
-            .context("Failed to execute 'gh issue create'")?
-    };
+const DEFAULT_PR_BODY: &str = "{closes}
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        eprintln!("{}", stderr);
-        bail!("Failed to create GitHub issue.");
-    }
+## Changes
 
-    // gh issue create outputs URL like: https://github.com/owner/repo/issues/123
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let url = stdout.trim();
+{commits}
 
-    // Extract issue number from URL
-    let issue_id = url
-        .rsplit('/')
-        .next()
-        .context("Failed to parse issue number from gh output")?
-        .to_string();
+{checks}Implemented by {agent} ({model}) with fuzemill.
 
-    if issue_id.is_empty() || !issue_id.chars().all(|c| c.is_ascii_digit()) {
-        bail!("Failed to extract issue number from: {}", url);
+## Checklist
+
+- [ ] Changes reviewed by a human
+- [ ] Tests added or updated
+- [ ] Documentation updated if needed
+";
+
+fn load_config(git_root: &Path) -> Result<Config> {
+    let (main_repo_path, _) = get_git_common_dir(git_root)?;
+    let path = main_repo_path.join(".fuzemill.toml");
+    if !path.exists() {
+        return Ok(Config::default());
     }
 
-    println!("Created GitHub issue: {} ({})", issue_id.green(), url);
-    Ok(issue_id)
+    let data = fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    toml::from_str(&data).with_context(|| format!("Failed to parse {}", path.display()))
 }
 
-fn handle_unstart(issue_id: String, verbose: bool) -> Result<()> {
-    let current_dir = env::current_dir().context("Failed to get current directory")?;
-    let git_root = find_git_root(&current_dir).context("Not in a git repository")?;
-    
-    let (main_repo_path, is_worktree) = get_git_common_dir(&git_root)?;
+// Replaces {name} placeholders in one pass, so placeholders in the values are left as they are.
+// Unknown placeholders are left as they are too.
+fn render_template(template: &str, vars: &[(&str, &str)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest[1..]
+            .find(['{', '}'])
+            .filter(|&end| rest.as_bytes()[end + 1] == b'}')
+            .and_then(|end| {
+                let name = &rest[1..end + 1];
+                vars.iter().find(|(n, _)| *n == name).map(|(_, value)| (*value, end + 2))
+            });
+        match value {
+            Some((value, len)) => {
+                out.push_str(value);
+                rest = &rest[len..];
+            }
+            None => {
+                out.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
 
-    // Construct expected path for the issue
-    // We need to guess where it is. Assuming sibling convention used in Start.
-    // If we are IN the worktree to be deleted, we know the path is git_root.
-    
-    // Case 1: We are inside the worktree we want to delete
-    // We verify if the branch matches the issue_id
-    let current_branch = get_current_branch()?;
-    
-    let worktree_to_remove;
-    let branch_to_remove = issue_id.clone(); // Assume branch name is issue_id
+// Issue-level state, stored as issue.json next to the issue's runs
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct IssueRecord {
+    pr_url: Option<String>,
+    // Agents racing on the issue, until one is picked
+    candidates: Vec<String>,
+    // Total token usage of the issue's runs
+    usage: Usage,
+    // "merged", "abandoned" (unstarted) or "discarded" (lost a race); open if not set
+    outcome: Option<String>,
+    // Issue whose branch this one is stacked on; its PR targets that branch until it is merged
+    base: Option<String>,
+}
 
-    if is_worktree && current_branch == issue_id {
-        worktree_to_remove = git_root.clone();
-        if verbose {
-            println!("Detected we are inside the worktree to remove.");
-        }
-        
-        // We need to move out before deleting.
-        // Move to main repo.
-        env::set_current_dir(&main_repo_path).context("Failed to change directory to main repo")?;
-        println!("Moved to main repo: {}", main_repo_path.display());
-    } else {
-        // Case 2: We are outside (maybe in main), asking to delete a sibling worktree
-        // We reconstruct the path
-        let repo_dirname = main_repo_path.file_name().and_then(|n| n.to_str()).unwrap_or("unknown");
-        let dir_name = format!("{}-{}", repo_dirname, issue_id);
-        // Assuming sibling of main repo
-        let probable_path = main_repo_path.parent().unwrap().join(dir_name);
-        
-        if probable_path.exists() {
-            worktree_to_remove = probable_path;
-        } else {
-            // Try to look it up via 'git worktree list'?
-            // For now, fail if not found at expected location
-             bail!("Could not find worktree at expected path: {}", probable_path.display());
+impl IssueRecord {
+    fn load(git_root: &Path, issue_id: &str) -> Result<Self> {
+        let path = issue_state_dir(git_root, issue_id)?.join("issue.json");
+        if !path.exists() {
+            return Ok(IssueRecord::default());
         }
+        let data = fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        serde_json::from_str(&data).with_context(|| format!("Failed to parse {}", path.display()))
     }
 
-    if verbose {
-        println!("Removing worktree: {}", worktree_to_remove.display());
+    fn save(&self, git_root: &Path, issue_id: &str) -> Result<()> {
+        let dir = issue_state_dir(git_root, issue_id)?;
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        let path = dir.join("issue.json");
+        let data = serde_json::to_string_pretty(self)?;
+        fs::write(&path, data).with_context(|| format!("Failed to write {}", path.display()))
     }
+}
 
-    // git worktree remove <path>
-    let status = Command::new("git")
-        .arg("worktree")
-        .arg("remove")
-        .arg(&worktree_to_remove)
-        .status()
-        .context("Failed to execute git worktree remove")?;
+// Metadata for one agent session, stored as run.json in its run directory
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RunRecord {
+    issue_id: String,
+    agent: String,
+    model: Option<String>,
+    started_at: String,
+    ended_at: Option<String>,
+    base_commit: Option<String>,
+    head_commit: Option<String>,
+    exit_code: Option<i32>,
+    // Results of the latest run of the configured checks, and the commit they ran on
+    #[serde(default)]
+    checks: Vec<CheckResult>,
+    #[serde(default)]
+    checked_commit: Option<String>,
+    // Set when the agent ended the session with 'done'
+    #[serde(default)]
+    done: bool,
+    // The limit the session exceeded, if any
+    #[serde(default)]
+    limit: Option<String>,
+    // Token usage read from the agent's logs once the session ended
+    #[serde(default)]
+    usage: Option<Usage>,
+    // Where the agent ran; runs recorded before there was a choice ran in tmux
+    #[serde(default)]
+    runner: Runner,
+}
 
-    if !status.success() {
-        // Sometimes force is needed if modified files?
-        // For now, let it fail.
-        bail!("git worktree remove failed");
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CheckResult {
+    name: String,
+    command: String,
+    exit_code: Option<i32>,
+    duration_secs: u64,
+}
+
+impl CheckResult {
+    fn passed(&self) -> bool {
+        self.exit_code == Some(0)
     }
+}
 
-    // git branch -D <issue_id>
-    let status = Command::new("git")
-        .arg("branch")
-        .arg("-D")
-        .arg(&branch_to_remove)
-        .status()
-        .context("Failed to delete branch")?;
+impl RunRecord {
+    fn load(run_dir: &Path) -> Result<Self> {
+        let path = run_dir.join("run.json");
+        let data = fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        serde_json::from_str(&data).with_context(|| format!("Failed to parse {}", path.display()))
+    }
 
-    if !status.success() {
-        println!("{}", "Warning: Failed to delete branch (maybe it was already deleted or different name?)".yellow());
-    } else {
-        println!("Deleted branch {}", branch_to_remove);
+    fn save(&self, run_dir: &Path) -> Result<()> {
+        let path = run_dir.join("run.json");
+        let data = serde_json::to_string_pretty(self)?;
+        fs::write(&path, data).with_context(|| format!("Failed to write {}", path.display()))
     }
-    
-    // If we were inside the worktree, we are now in main_repo (due to set_current_dir).
-    // We should spawn a shell there so the user feels "cd'ed back".
-    if is_worktree && current_branch == issue_id {
-        println!("Spawning subshell in {}", main_repo_path.display().to_string().green());
-        spawn_shell(&main_repo_path)?;
+
+    // Records the end of the run; a no-op if it was already recorded
+    fn finish(&mut self, worktree_path: &Path, exit_code: Option<i32>) {
+        if self.ended_at.is_some() {
+            return;
+        }
+        self.ended_at = Some(utc_timestamp(unix_now()));
+        self.head_commit = git_head(worktree_path);
+        self.exit_code = exit_code;
     }
 
-    Ok(())
+    fn commit_range(&self) -> Option<String> {
+        match (&self.base_commit, &self.head_commit) {
+            (Some(base), Some(head)) => Some(format!("{}..{}", short_sha(base), short_sha(head))),
+            _ => None,
+        }
+    }
 }
 
-fn spawn_shell(path: &Path) -> Result<()> {
-    let shell = env::var("SHELL").unwrap_or_else(|_| "sh".to_string());
-    let mut command = Command::new(shell);
-    command.current_dir(path);
-    
-    let status = command.status().context("Failed to spawn shell")?;
-    
-    if !status.success() {
-        bail!("Shell exited with non-zero status");
+// Returns the run directories of an issue, oldest first
+fn list_runs(git_root: &Path, issue_id: &str) -> Result<Vec<PathBuf>> {
+    let runs_dir = issue_state_dir(git_root, issue_id)?.join("runs");
+    if !runs_dir.exists() {
+        return Ok(Vec::new());
     }
-    Ok(())
+
+    let mut runs: Vec<PathBuf> = fs::read_dir(&runs_dir)
+        .with_context(|| format!("Failed to read {}", runs_dir.display()))?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.is_dir())
+        .collect();
+    runs.sort();
+    Ok(runs)
 }
 
-fn get_current_branch() -> Result<String> {
+fn git_head(cwd: &Path) -> Option<String> {
+    git_rev_parse(cwd, "HEAD")
+}
+
+fn git_rev_parse(cwd: &Path, rev: &str) -> Option<String> {
     let output = Command::new("git")
         .arg("rev-parse")
-        .arg("--abbrev-ref")
-        .arg("HEAD")
+        .arg(rev)
+        .current_dir(cwd)
         .output()
-        .context("Failed to get current branch")?;
-        
-    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
-}
+        .ok()?;
 
-// Returns (main_repo_path, is_worktree)
-fn get_git_common_dir(git_root: &Path) -> Result<(PathBuf, bool)> {
-    // Check if .git is a file (worktree) or dir (main repo)
-    let git_item = git_root.join(".git");
-    if git_item.is_file() {
-        // It's a worktree. 
-        // We can find the main dir by parsing the .git file or asking git
-        let output = Command::new("git")
-            .arg("rev-parse")
-            .arg("--path-format=absolute")
-            .arg("--git-common-dir")
-            .current_dir(git_root)
-            .output()
-            .context("Failed to get git common dir")?;
-            
-        let common_dir_str = String::from_utf8_lossy(&output.stdout).trim().to_string();
-        let common_dir = PathBuf::from(common_dir_str);
-        
-        // common_dir usually points to .git inside main repo. Parent is main repo.
-        let main_repo = common_dir.parent().unwrap_or(&common_dir).to_path_buf();
-        Ok((main_repo, true))
-    } else {
-        Ok((git_root.to_path_buf(), false))
+    if !output.status.success() {
+        return None;
     }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn short_sha(sha: &str) -> &str {
+    &sha[..sha.len().min(8)]
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// Formats seconds since the epoch as an RFC 3339 UTC timestamp, e.g. 2025-01-31T09:05:00Z
+fn utc_timestamp(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+
+    // Civil-from-days (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, rem / 3_600, rem % 3_600 / 60, rem % 60)
+}
+
+// Inverse of utc_timestamp
+fn parse_utc_timestamp(s: &str) -> Option<u64> {
+    let (date, time) = s.strip_suffix('Z')?.split_once('T')?;
+    let mut date = date.split('-').map(|p| p.parse::<i64>());
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    let mut time = time.split(':').map(|p| p.parse::<u64>());
+    let (hour, minute, second) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+
+    // Days-from-civil (Howard Hinnant's algorithm)
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+
+    Some(days as u64 * 86_400 + hour * 3_600 + minute * 60 + second)
 }
 
 fn find_git_root(start_path: &Path) -> Option<PathBuf> {
@@ -859,4 +7568,387 @@ fn check_issue_exists_github(issue_id: &str, cwd: &Path, verbose: bool) -> Resul
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gc_only_kills_sessions_of_this_repository() {
+        let worktree = Path::new("/src/repo-42");
+        assert!(is_orphaned_session(Some(worktree), worktree, false));
+        // Still has its worktree
+        assert!(!is_orphaned_session(Some(worktree), worktree, true));
+        // Same branch name, started in another repository's worktree
+        assert!(!is_orphaned_session(Some(Path::new("/src/other-42")), worktree, false));
+        // Hosts that don't report where the session was started
+        assert!(!is_orphaned_session(None, worktree, false));
+    }
+
+    #[test]
+    fn strip_ansi_drops_escape_sequences() {
+        assert_eq!(strip_ansi("\x1b[1;32mok\x1b[0m done"), "ok done");
+        assert_eq!(strip_ansi("\x1b]0;title\x07prompt \x1b]8;;https://example.com\x1b\\link"), "prompt link");
+        assert_eq!(strip_ansi("a\x08b\tc\r\n"), "ab\tc\r\n");
+    }
+
+    #[test]
+    fn condense_transcript_keeps_the_final_state_of_redrawn_lines() {
+        let raw = "\n\nBuilding\r\nBuilding\r\n\x1b[2K10%\r50%\r100%\r\n\r\n\r\n\r\nDone\n\n";
+        assert_eq!(condense_transcript(raw, 100), "Building\n100%\n\nDone");
+        assert_eq!(condense_transcript(raw, 2), "\nDone");
+    }
+
+    #[test]
+    fn render_template_substitutes_known_placeholders() {
+        let vars = [("issue", "42"), ("branch", "fix-login")];
+        assert_eq!(render_template("Issue {issue} on '{branch}'", &vars), "Issue 42 on 'fix-login'");
+        assert_eq!(render_template("{issue}{issue}", &vars), "4242");
+        assert_eq!(render_template("{unknown} {issue", &vars), "{unknown} {issue");
+        assert_eq!(render_template("{{issue}}", &vars), "{42}");
+    }
+
+    #[test]
+    fn render_template_copies_values_verbatim() {
+        let vars = [("title", "Support {branch} in names"), ("branch", "42")];
+        assert_eq!(render_template("{title} ({branch})", &vars), "Support {branch} in names (42)");
+    }
+
+    #[test]
+    fn trim_ci_log_keeps_the_lines_around_errors() {
+        let mut lines: Vec<String> = (1..=20).map(|i| format!("step {}", i)).collect();
+        lines[0] = "error: first".to_string();
+        lines[19] = "thread 'main' panicked".to_string();
+        let log: Vec<String> = lines.iter().map(|l| format!("test\tRun tests\t2024-01-01T00:00:00.0000000Z {}", l)).collect();
+
+        let expected = ["error: first", "step 2", "step 3", "step 4", "step 5", "step 6", "...", "step 15", "step 16", "step 17", "step 18", "step 19", "thread 'main' panicked"];
+        assert_eq!(trim_ci_log(&log.join("\n"), 100), expected.join("\n"));
+        assert_eq!(trim_ci_log(&log.join("\n"), 2), "step 19\nthread 'main' panicked");
+    }
+
+    #[test]
+    fn trim_ci_log_falls_back_to_the_end_of_the_log() {
+        assert_eq!(trim_ci_log("\x1b[32mstep 1\x1b[0m\nstep 2\nstep 3", 2), "step 2\nstep 3");
+    }
+
+    // A scratch directory for a test, emptied first
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("fuzemill-test-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git").args(args).current_dir(dir).status().unwrap();
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    // A repository with one commit, holding the given .fuzemill.toml
+    fn test_repo(name: &str, config: &str) -> PathBuf {
+        let dir = test_dir(name);
+        git(&dir, &["init", "-q"]);
+        git(&dir, &["config", "user.name", "Test"]);
+        git(&dir, &["config", "user.email", "test@example.com"]);
+        git(&dir, &["config", "commit.gpgsign", "false"]);
+        fs::write(dir.join(".fuzemill.toml"), config).unwrap();
+        git(&dir, &["add", ".fuzemill.toml"]);
+        git(&dir, &["commit", "-q", "-m", "init"]);
+        dir
+    }
+
+    #[test]
+    fn verify_checks_records_the_results_in_the_run() {
+        let repo = test_repo("checks", "[[checks]]\nrun = \"true\"\n\n[[checks]]\nname = \"lint\"\nrun = \"echo bad; exit 3\"\n");
+        let run_dir = create_run_dir(&repo, "42").unwrap();
+        RunRecord::default().save(&run_dir).unwrap();
+
+        assert!(!verify_checks(&repo, Some(&run_dir), false).unwrap());
+        let run = RunRecord::load(&run_dir).unwrap();
+        let results: Vec<(&str, &str, Option<i32>)> = run.checks.iter().map(|c| (c.name.as_str(), c.command.as_str(), c.exit_code)).collect();
+        assert_eq!(results, [("true", "true", Some(0)), ("lint", "echo bad; exit 3", Some(3))]);
+        assert_eq!(run.checked_commit, git_head(&repo));
+        let log = fs::read_to_string(run_dir.join("checks.log")).unwrap();
+        assert!(log.contains("==> lint (echo bad; exit 3): exit code 3\nbad\n"), "{}", log);
+    }
+
+    #[test]
+    fn verify_checks_reruns_only_when_the_worktree_changed() {
+        let marker = test_dir("checks-marker").join("fail");
+        let repo = test_repo("checks-rerun", &format!("[[checks]]\nrun = \"test ! -e {}\"\n", marker.display()));
+        let run_dir = create_run_dir(&repo, "42").unwrap();
+        RunRecord::default().save(&run_dir).unwrap();
+        assert!(verify_checks(&repo, Some(&run_dir), false).unwrap());
+
+        // The check would fail now, but it already passed on this commit
+        fs::write(&marker, "").unwrap();
+        assert!(verify_checks(&repo, Some(&run_dir), false).unwrap());
+
+        fs::write(repo.join("new.txt"), "").unwrap();
+        assert!(!verify_checks(&repo, Some(&run_dir), false).unwrap());
+        assert!(!RunRecord::load(&run_dir).unwrap().checks[0].passed());
+    }
+
+    #[test]
+    fn exceeded_limit_reports_the_first_limit_reached() {
+        let repo = test_repo("limits", "");
+        let base = git_head(&repo);
+        git(&repo, &["checkout", "-q", "-b", "42"]);
+        git(&repo, &["commit", "-q", "--allow-empty", "-m", "one"]);
+        git(&repo, &["commit", "-q", "--allow-empty", "-m", "two"]);
+        let now = unix_now();
+        let run_dir = create_run_dir(&repo, "42").unwrap();
+        let run = RunRecord {
+            started_at: utc_timestamp(now - 3_600),
+            base_commit: base,
+            ..Default::default()
+        };
+        run.save(&run_dir).unwrap();
+
+        let exceeded = |wall_clock_minutes, idle_minutes, max_commits, idle_secs| {
+            let limits = LimitsConfig { wall_clock_minutes, idle_minutes, max_commits, policy: LimitPolicy::default() };
+            exceeded_limit(&repo, "42", &limits, idle_secs, now).unwrap()
+        };
+        assert_eq!(exceeded(None, None, None, 0), None);
+        assert_eq!(exceeded(Some(60), None, None, 0).as_deref(), Some("ran for more than 60 minutes"));
+        assert_eq!(exceeded(Some(61), Some(5), None, 299), None);
+        assert_eq!(exceeded(Some(61), Some(5), None, 300).as_deref(), Some("produced no output for 5 minutes"));
+        assert_eq!(exceeded(None, None, Some(2), 0), None);
+        assert_eq!(exceeded(None, None, Some(1), 0).as_deref(), Some("made 2 commits (limit 1)"));
+        assert_eq!(exceeded(Some(30), Some(1), Some(1), 600).as_deref(), Some("ran for more than 30 minutes"));
+    }
+
+    #[test]
+    fn limit_policy_defaults_to_interrupt() {
+        let policy = |toml: &str| toml::from_str::<LimitsConfig>(toml).map(|l| l.policy);
+        assert_eq!(policy("").unwrap(), LimitPolicy::Interrupt);
+        assert_eq!(policy("policy = \"salvage\"").unwrap(), LimitPolicy::Salvage);
+        assert_eq!(policy("policy = \"end\"").unwrap(), LimitPolicy::End);
+        assert!(policy("policy = \"kill\"").is_err());
+    }
+
+    // Log timestamps within 09:05 and 10:00 on 2025-01-31
+    fn in_test_run(ts: &str) -> bool {
+        let ts = ts.get(..19).unwrap_or(ts);
+        ("2025-01-31T09:05:00"..="2025-01-31T10:00:00").contains(&ts)
+    }
+
+    #[test]
+    fn claude_usage_counts_each_response_of_the_run_once() {
+        let dir = test_dir("claude-usage");
+        let log = [
+            r#"{"timestamp":"2025-01-31T09:10:00.123Z","message":{"id":"m1","model":"claude-sonnet-4","usage":{"input_tokens":1000,"output_tokens":100,"cache_read_input_tokens":10000}}}"#,
+            r#"{"timestamp":"2025-01-31T09:10:01.000Z","message":{"id":"m1","model":"claude-sonnet-4","usage":{"input_tokens":1000,"output_tokens":100,"cache_read_input_tokens":10000}}}"#,
+            r#"{"timestamp":"2025-01-31T09:20:00.000Z","message":{"id":"m2","model":"claude-opus-4","usage":{"input_tokens":10,"output_tokens":20}},"costUSD":0.5}"#,
+            r#"{"timestamp":"2025-01-31T08:00:00.000Z","message":{"id":"m3","model":"claude-opus-4","usage":{"input_tokens":99,"output_tokens":99}}}"#,
+            r#"{"timestamp":"2025-01-31T09:30:00.000Z","message":{"role":"user","content":"Go on"}}"#,
+        ];
+        fs::write(dir.join("session.jsonl"), log.join("\n")).unwrap();
+        fs::write(dir.join("notes.txt"), log[3]).unwrap();
+
+        let usage = claude_usage(&dir, &in_test_run, &[]).unwrap();
+        assert_eq!((usage.input_tokens, usage.output_tokens, usage.cache_read_tokens, usage.cache_write_tokens), (1010, 120, 10000, 0));
+        // Sonnet's default prices for the first response, the logged cost for the second
+        assert!((usage.cost_usd - 0.5075).abs() < 1e-9, "{}", usage.cost_usd);
+        assert_eq!(usage.models, ["claude-sonnet-4", "claude-opus-4"]);
+    }
+
+    #[test]
+    fn gemini_usage_separates_cached_input_and_counts_thoughts() {
+        let dir = test_dir("gemini-usage");
+        let chat = r#"{"messages":[
+            {"type":"user","timestamp":"2025-01-31T09:09:00.000Z","content":"Fix it"},
+            {"type":"gemini","timestamp":"2025-01-31T09:10:00.000Z","model":"gemini-2.5-pro","tokens":{"input":1000,"output":100,"cached":400,"thoughts":50}},
+            {"type":"gemini","timestamp":"2025-01-31T10:10:00.000Z","model":"gemini-2.5-pro","tokens":{"input":5000,"output":500}}
+        ]}"#;
+        fs::write(dir.join("session-1.json"), chat).unwrap();
+        let pricing = [PriceConfig {
+            model: "gemini-2.5-pro".to_string(),
+            input: 1.0,
+            output: 10.0,
+            cache_read: 0.5,
+            cache_write: 0.0,
+        }];
+
+        let usage = gemini_usage(&dir, &in_test_run, &pricing).unwrap();
+        assert_eq!((usage.input_tokens, usage.output_tokens, usage.cache_read_tokens), (600, 150, 400));
+        assert!((usage.cost_usd - 0.0023).abs() < 1e-9, "{}", usage.cost_usd);
+        assert!(gemini_usage(&test_dir("gemini-usage-empty"), &in_test_run, &pricing).is_none());
+    }
+
+    #[test]
+    fn csv_field_quotes_only_when_needed() {
+        assert_eq!(csv_field("claude-sonnet-4"), "claude-sonnet-4");
+        assert_eq!(csv_field("bug, ui"), "\"bug, ui\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+    }
+
+    #[test]
+    fn utc_timestamps_round_trip() {
+        for secs in [0, 951_782_400, 1_738_314_300, 4_107_542_399] {
+            assert_eq!(parse_utc_timestamp(&utc_timestamp(secs)), Some(secs));
+        }
+        assert_eq!(utc_timestamp(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(utc_timestamp(1_738_314_300), "2025-01-31T09:05:00Z");
+        assert_eq!(parse_utc_timestamp("2025-01-31T09:05:00"), None);
+        assert_eq!(parse_utc_timestamp("2025-01-31 09:05:00Z"), None);
+    }
+
+    #[test]
+    fn headless_usage_reads_the_result_line() {
+        let output = [
+            r#"{"type":"assistant","message":{"id":"m1"}}"#,
+            "not json",
+            r#"{"type":"result","total_cost_usd":0.12,"usage":{"input_tokens":100,"output_tokens":20,"cache_read_input_tokens":5,"cache_creation_input_tokens":1},"modelUsage":{"claude-sonnet-4":{}}}"#,
+        ]
+        .join("\n");
+        let usage = headless_usage(&output).unwrap();
+        assert_eq!((usage.input_tokens, usage.output_tokens, usage.cache_read_tokens, usage.cache_write_tokens), (100, 20, 5, 1));
+        assert_eq!(usage.cost_usd, 0.12);
+        assert_eq!(usage.models, ["claude-sonnet-4"]);
+        assert!(headless_usage(r#"{"type":"assistant","message":{"id":"m1"}}"#).is_none());
+    }
+
+    #[test]
+    fn shell_quote_quotes_only_when_needed() {
+        assert_eq!(shell_quote("/usr/local/bin/fuzemill"), "/usr/local/bin/fuzemill");
+        assert_eq!(shell_quote("--id=42"), "--id=42");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("two words"), "'two words'");
+        assert_eq!(shell_quote("it's $HOME"), "'it'\\''s $HOME'");
+    }
+
+    #[test]
+    fn tmux_quote_escapes_quotes_and_variables() {
+        assert_eq!(tmux_quote("fuzemill cleanup 42"), "\"fuzemill cleanup 42\"");
+        assert_eq!(tmux_quote("say \"hi\" to $USER\\n"), "\"say \\\"hi\\\" to \\$USER\\\\n\"");
+    }
+
+    #[test]
+    fn say_pastes_the_message_and_sends_enter_separately() {
+        // Control characters in the message are pasted as text, so they can't submit it early
+        assert_eq!(screen_input(Keys::Paste("a^Mb\\n")), "\\033[200~a\\^Mb\\\\n\\033[201~");
+        assert_eq!(screen_input(Keys::Enter), "^M");
+        assert!(paste_settle_time("claude") > paste_settle_time("gemini"));
+        assert!(paste_settle_time("gemini") >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn host_allowed_matches_exact_hosts() {
+        let allow = vec!["api.anthropic.com".to_string()];
+        assert!(host_allowed("api.anthropic.com", &allow));
+        assert!(host_allowed("API.Anthropic.com", &allow));
+        assert!(!host_allowed("anthropic.com", &allow));
+        assert!(!host_allowed("evil.api.anthropic.com", &allow));
+        assert!(!host_allowed("api.anthropic.com.evil.net", &allow));
+    }
+
+    #[test]
+    fn host_allowed_matches_subdomains_of_wildcards() {
+        let allow = vec!["*.github.com".to_string()];
+        assert!(host_allowed("api.github.com", &allow));
+        assert!(host_allowed("a.b.github.com", &allow));
+        assert!(!host_allowed("github.com", &allow));
+        assert!(!host_allowed("evilgithub.com", &allow));
+    }
+
+    #[test]
+    fn host_allowed_handles_ipv6_and_empty_allowlist() {
+        assert!(host_allowed("[::1]", &["::1".to_string()]));
+        assert!(!host_allowed("example.com", &[]));
+    }
+
+    fn test_sandbox_paths() -> SandboxPaths {
+        SandboxPaths {
+            worktree: PathBuf::from("/src/repo-42"),
+            common_dir: PathBuf::from("/src/repo/.git"),
+            worktree_git_dir: Some(PathBuf::from("/src/repo/.git/worktrees/repo-42")),
+            outbox: PathBuf::from("/src/repo/.git/fuzemill/issues/42/runs/1/sandbox"),
+            current_exe: PathBuf::from("/usr/local/bin/fuzemill"),
+        }
+    }
+
+    // Position of a mount, which has to come after the mounts it overrides
+    fn mount_position(args: &[String], flag: &str, path: &str) -> usize {
+        args.windows(3)
+            .position(|w| w[0] == flag && w[1] == path && w[2] == path)
+            .unwrap_or_else(|| panic!("{} {} missing from {:?}", flag, path, args))
+    }
+
+    #[test]
+    fn bwrap_mounts_git_config_and_state_read_only() {
+        let paths = test_sandbox_paths();
+        let cmd = bwrap_command(&SandboxConfig::default(), &paths, &[], "claude");
+        let args: Vec<String> = cmd.get_args().map(|a| a.to_string_lossy().to_string()).collect();
+
+        let common = mount_position(&args, "--bind", "/src/repo/.git");
+        let worktree = mount_position(&args, "--bind", "/src/repo-42");
+        for path in [
+            "/src/repo/.git/config",
+            "/src/repo/.git/hooks",
+            "/src/repo/.git/info",
+            "/src/repo/.git/fuzemill",
+            "/src/repo/.git/worktrees/repo-42/config.worktree",
+            "/src/repo/.git/worktrees/repo-42/commondir",
+            "/src/repo/.git/worktrees/repo-42/gitdir",
+            "/src/repo-42/.git",
+        ] {
+            let read_only = mount_position(&args, "--ro-bind", path);
+            assert!(read_only > common && read_only > worktree, "{} is mounted before the writable mounts", path);
+        }
+        let state = mount_position(&args, "--ro-bind", "/src/repo/.git/fuzemill");
+        assert!(mount_position(&args, "--bind", "/src/repo/.git/fuzemill/issues/42/runs/1/sandbox") > state);
+        assert_eq!(args.last().map(String::as_str), Some("claude"));
+    }
+
+    #[test]
+    fn podman_mounts_git_config_and_state_read_only() {
+        let paths = test_sandbox_paths();
+        let config = SandboxConfig {
+            image: Some("rust:1".to_string()),
+            read_only: Vec::new(),
+            ..SandboxConfig::default()
+        };
+        let cmd = podman_command(&config, "fuzemill-1", &paths, &[], "claude").unwrap();
+        let volumes: Vec<String> = cmd
+            .get_args()
+            .map(|a| a.to_string_lossy().to_string())
+            .collect::<Vec<_>>()
+            .windows(2)
+            .filter(|w| w[0] == "--volume")
+            .map(|w| w[1].clone())
+            .collect();
+
+        assert_eq!(
+            volumes,
+            [
+                "/src/repo/.git:/src/repo/.git",
+                "/src/repo-42:/src/repo-42",
+                "/src/repo/.git/config:/src/repo/.git/config:ro",
+                "/src/repo/.git/hooks:/src/repo/.git/hooks:ro",
+                "/src/repo/.git/info:/src/repo/.git/info:ro",
+                "/src/repo/.git/fuzemill:/src/repo/.git/fuzemill:ro",
+                "/src/repo/.git/worktrees/repo-42/config.worktree:/src/repo/.git/worktrees/repo-42/config.worktree:ro",
+                "/src/repo/.git/worktrees/repo-42/commondir:/src/repo/.git/worktrees/repo-42/commondir:ro",
+                "/src/repo/.git/worktrees/repo-42/gitdir:/src/repo/.git/worktrees/repo-42/gitdir:ro",
+                "/src/repo-42/.git:/src/repo-42/.git:ro",
+                "/src/repo/.git/fuzemill/issues/42/runs/1/sandbox:/src/repo/.git/fuzemill/issues/42/runs/1/sandbox",
+                "/usr/local/bin/fuzemill:/usr/local/bin/fuzemill:ro",
+            ]
+        );
+    }
+
+    #[test]
+    fn task_list_references_reads_unchecked_items() {
+        let body = "Blocked on:\n- [ ] #12\n- [x] #13\n  * [ ] Finish #14 and #15\n- #16\nSee #17";
+        assert_eq!(task_list_references(body), vec![12, 14, 15]);
+    }
+
+    #[test]
+    fn task_list_references_ignores_non_numbers() {
+        let body = "- [ ] #abc\n- [ ] issue#18\n- [ ] (#19)";
+        assert_eq!(task_list_references(body), vec![19]);
+    }
+}